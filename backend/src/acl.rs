@@ -0,0 +1,311 @@
+//! Path-based access control lists, which restrict users and tokens to globs of repository paths.
+
+use super::*;
+use globset::{GlobBuilder, GlobMatcher};
+use sqlx::sqlite::SqliteRow;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Access {
+    Read,
+    /// Implies read access.
+    Write,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct AclRule {
+    pub id: i64,
+    /// A user name, `token:<id>` for a personal access token, or `*` for all non-admin users.
+    pub subject: String,
+    /// A glob of repository paths, where `*` does not match `/` but `**` does.
+    pub pattern: String,
+    pub access: Access,
+}
+
+impl AclRule {
+    fn from_row(row: SqliteRow) -> Self {
+        AclRule {
+            id: row.get("id"),
+            subject: row.get("subject"),
+            pattern: row.get("pattern"),
+            access: serde_json::from_value(serde_json::Value::String(row.get("access"))).unwrap(),
+        }
+    }
+
+    pub fn matcher(pattern: &str) -> Result<GlobMatcher> {
+        let glob = GlobBuilder::new(pattern)
+            .literal_separator(true)
+            .build()
+            .with_context(|| format!("Invalid glob: {}", pattern))?;
+        Ok(glob.compile_matcher())
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct NewAclRule {
+    pub subject: String,
+    pub pattern: String,
+    pub access: Access,
+}
+
+/// Paths a principal may read or write according to ACL rules.
+///
+/// A path must be allowed by every rule set, and no rule sets means no restriction.
+#[derive(Debug, Clone, Default)]
+pub struct PathAccess {
+    rule_sets: Vec<Vec<(GlobMatcher, Access)>>,
+}
+
+impl PathAccess {
+    pub fn add_rule_set(&mut self, rules: Vec<AclRule>) {
+        if rules.is_empty() {
+            return;
+        }
+        let rules = rules.into_iter()
+            .filter_map(|rule| match AclRule::matcher(&rule.pattern) {
+                Ok(matcher) => Some((matcher, rule.access)),
+                Err(e) => {
+                    tracing::warn!("Ignoring ACL rule {}: {:?}", rule.id, e);
+                    None
+                },
+            })
+            .collect();
+        self.rule_sets.push(rules);
+    }
+
+    pub fn can_read(&self, path: &str) -> bool {
+        self.allows(path, Access::Read)
+    }
+
+    pub fn can_write(&self, path: &str) -> bool {
+        self.allows(path, Access::Write)
+    }
+
+    fn allows(&self, path: &str, access: Access) -> bool {
+        self.rule_sets.iter().all(|rules| {
+            rules.iter().any(|(matcher, granted)| {
+                (*granted == Access::Write || access == Access::Read) && matcher.is_match(path)
+            })
+        })
+    }
+}
+
+/// Whether `path` is `prefix` or under it; an empty prefix matches every path.
+pub fn path_has_prefix(path: &str, prefix: &str) -> bool {
+    let prefix = prefix.trim_end_matches('/');
+    prefix.is_empty() || path == prefix || path.strip_prefix(prefix).is_some_and(|rest| rest.starts_with('/'))
+}
+
+impl AppState {
+    /// Resolve the ACL rules that apply to `user`, optionally through a personal access token.
+    pub async fn path_access(&self, user: &User, token_id: Option<&str>) -> Result<PathAccess> {
+        let mut access = PathAccess::default();
+
+        // Administrators are restricted only by the rules of their tokens
+        if !user.admin {
+            let rules = sqlx::query("SELECT * FROM acl_rule WHERE subject = ? OR subject = '*';")
+                .bind(&user.name)
+                .map(AclRule::from_row)
+                .fetch_all(&self.cache_db)
+                .await?;
+            access.add_rule_set(rules);
+        }
+        if let Some(token_id) = token_id {
+            let rules = sqlx::query("SELECT * FROM acl_rule WHERE subject = ?;")
+                .bind(format!("token:{}", token_id))
+                .map(AclRule::from_row)
+                .fetch_all(&self.cache_db)
+                .await?;
+            access.add_rule_set(rules);
+        }
+
+        Ok(access)
+    }
+
+    pub async fn list_acl_rules(&self) -> Result<Vec<AclRule>> {
+        let rules = sqlx::query("SELECT * FROM acl_rule ORDER BY id;")
+            .map(AclRule::from_row)
+            .fetch_all(&self.cache_db)
+            .await?;
+        Ok(rules)
+    }
+}
+
+pub async fn get_admin_acl(
+    extract::State(state): extract::State<AppState>,
+) -> Result<Json<Vec<AclRule>>, AppError> {
+    tracing::debug!("acl::get_admin_acl");
+    Ok(Json(state.list_acl_rules().await?))
+}
+
+pub async fn post_admin_acl(
+    extract::State(state): extract::State<AppState>,
+    AppJson(new_rule): AppJson<NewAclRule>,
+) -> Result<Response, AppError> {
+    tracing::debug!("acl::post_admin_acl");
+
+    if new_rule.subject.is_empty() {
+        return Err(AppError::BadRequest("a subject is required".to_owned()));
+    }
+    if let Err(e) = AclRule::matcher(&new_rule.pattern) {
+        return Err(AppError::BadRequest(format!("invalid pattern: {}", e)));
+    }
+
+    let access = serde_json::to_value(new_rule.access)?;
+    let id: i64 = sqlx::query_scalar("INSERT INTO acl_rule (subject, pattern, access) VALUES (?, ?, ?) RETURNING id;")
+        .bind(&new_rule.subject)
+        .bind(&new_rule.pattern)
+        .bind(access.as_str())
+        .fetch_one(&state.cache_db_writer)
+        .await
+        .context("Failed to create an ACL rule")?;
+    tracing::info!("Granted {:?} access to {} for {}", new_rule.access, new_rule.pattern, new_rule.subject);
+
+    Ok((StatusCode::CREATED, Json(AclRule {
+        id,
+        subject: new_rule.subject,
+        pattern: new_rule.pattern,
+        access: new_rule.access,
+    })).into_response())
+}
+
+pub async fn delete_admin_acl_id(
+    AppPath(id): AppPath<i64>,
+    extract::State(state): extract::State<AppState>,
+) -> Result<Response, AppError> {
+    tracing::debug!("acl::delete_admin_acl_id");
+
+    let result = sqlx::query("DELETE FROM acl_rule WHERE id = ?;")
+        .bind(id)
+        .execute(&state.cache_db_writer)
+        .await
+        .context("Failed to delete an ACL rule")?;
+    if result.rows_affected() == 0 {
+        return Err(AppError::NotFound);
+    }
+    tracing::info!("Deleted ACL rule {}", id);
+    Ok(Json(&true).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn rule(pattern: &str, access: Access) -> AclRule {
+        AclRule { id: 0, subject: "alice".to_string(), pattern: pattern.to_string(), access }
+    }
+
+    #[test]
+    fn single_star_does_not_cross_directories() {
+        let matcher = AclRule::matcher("notes/*.md").unwrap();
+        assert!(matcher.is_match("notes/a.md"));
+        assert!(!matcher.is_match("notes/sub/a.md"));
+        assert!(!matcher.is_match("other/a.md"));
+    }
+
+    #[test]
+    fn double_star_crosses_directories() {
+        let matcher = AclRule::matcher("notes/**").unwrap();
+        assert!(matcher.is_match("notes/a.md"));
+        assert!(matcher.is_match("notes/sub/a.md"));
+        assert!(!matcher.is_match("notesx/a.md"));
+        let matcher = AclRule::matcher("**/*.png").unwrap();
+        assert!(matcher.is_match("a.png"));
+        assert!(matcher.is_match("notes/sub/a.png"));
+        assert!(!matcher.is_match("notes/a.md"));
+    }
+
+    #[test]
+    fn invalid_globs_are_errors() {
+        assert!(AclRule::matcher("notes/[").is_err());
+        assert!(AclRule::matcher("{a,b").is_err());
+    }
+
+    #[test]
+    fn no_rules_allow_everything() {
+        let access = PathAccess::default();
+        assert!(access.can_read("any/path.md"));
+        assert!(access.can_write("any/path.md"));
+    }
+
+    #[test]
+    fn write_implies_read() {
+        let mut access = PathAccess::default();
+        access.add_rule_set(vec![rule("public/**", Access::Read), rule("drafts/**", Access::Write)]);
+        assert!(access.can_read("public/a.md"));
+        assert!(!access.can_write("public/a.md"));
+        assert!(access.can_read("drafts/a.md"));
+        assert!(access.can_write("drafts/a.md"));
+        assert!(!access.can_read("private/a.md"));
+    }
+
+    #[test]
+    fn every_rule_set_must_allow() {
+        let mut access = PathAccess::default();
+        access.add_rule_set(vec![rule("notes/**", Access::Write)]);
+        access.add_rule_set(vec![rule("notes/shared/**", Access::Read)]);
+        // An empty rule set does not restrict
+        access.add_rule_set(vec![]);
+        assert!(access.can_read("notes/shared/a.md"));
+        assert!(!access.can_write("notes/shared/a.md"));
+        assert!(!access.can_read("notes/own/a.md"));
+    }
+
+    #[test]
+    fn invalid_rules_are_ignored() {
+        let mut access = PathAccess::default();
+        access.add_rule_set(vec![rule("notes/[", Access::Write), rule("public/**", Access::Read)]);
+        assert!(access.can_read("public/a.md"));
+        assert!(!access.can_read("notes/["));
+    }
+
+    #[test]
+    fn prefixes_match_whole_components() {
+        assert!(path_has_prefix("notes/a.md", "notes"));
+        assert!(path_has_prefix("notes/a.md", "notes/"));
+        assert!(path_has_prefix("notes", "notes"));
+        assert!(path_has_prefix("notes/a.md", ""));
+        assert!(!path_has_prefix("notesx/a.md", "notes"));
+        assert!(!path_has_prefix("notes", "notes/a.md"));
+    }
+
+    #[tokio::test]
+    async fn rules_apply_to_users_and_tokens() {
+        let (state, _dir) = testing::app_state().await;
+        testing::add_user(&state, "alice", "alice@example.com", "password").await;
+        testing::add_user(&state, "root", "root@example.com", "password").await;
+        sqlx::query("UPDATE user SET admin = 1 WHERE name = 'root';")
+            .execute(&state.cache_db_writer)
+            .await
+            .unwrap();
+        for (subject, pattern, access) in [("alice", "notes/**", "write"), ("*", "public/**", "read"), ("token:t1", "notes/todo.md", "read")] {
+            sqlx::query("INSERT INTO acl_rule (subject, pattern, access) VALUES (?, ?, ?);")
+                .bind(subject)
+                .bind(pattern)
+                .bind(access)
+                .execute(&state.cache_db_writer)
+                .await
+                .unwrap();
+        }
+        let alice = state.find_user("alice").await.unwrap().unwrap();
+        let root = state.find_user("root").await.unwrap().unwrap();
+
+        let access = state.path_access(&alice, None).await.unwrap();
+        assert!(access.can_write("notes/a.md"));
+        assert!(access.can_read("public/a.md"));
+        assert!(!access.can_read("private/a.md"));
+
+        // A token narrows the access of its user
+        let access = state.path_access(&alice, Some("t1")).await.unwrap();
+        assert!(access.can_read("notes/todo.md"));
+        assert!(!access.can_write("notes/todo.md"));
+        assert!(!access.can_read("notes/a.md"));
+
+        // Administrators are restricted only by their tokens
+        let access = state.path_access(&root, None).await.unwrap();
+        assert!(access.can_write("private/a.md"));
+        let access = state.path_access(&root, Some("t1")).await.unwrap();
+        assert!(!access.can_read("private/a.md"));
+    }
+}
@@ -0,0 +1,1126 @@
+//! Authentication with passwords, sessions and personal access tokens, and login throttling.
+
+use super::*;
+use std::sync::OnceLock;
+use std::time::Instant;
+use chrono::offset::TimeZone;
+use sqlx::sqlite::SqliteRow;
+
+/// The authenticated user of a request, inserted by the `auth` middleware.
+#[derive(Debug, Clone)]
+pub struct Principal {
+    pub user: String,
+    pub email: String,
+    pub display_name: Option<String>,
+    /// Set when authenticated with a session JWT.
+    pub session_id: Option<String>,
+    /// Set when authenticated with a personal access token.
+    pub token: Option<TokenGrant>,
+    pub acl: PathAccess,
+}
+
+impl Principal {
+    /// Git signature of the authenticated user, named by their display name if they have one.
+    pub fn signature(&self) -> Result<git2::Signature<'static>, git2::Error> {
+        git2::Signature::now(self.display_name.as_deref().unwrap_or(&self.user), &self.email)
+    }
+
+    /// Check whether `route` may be used on `path`, which is not part of the URL.
+    pub fn permits(&self, method: &Method, route: &str, path: &str) -> bool {
+        self.token.as_ref().is_none_or(|grant| grant.permits(method, route, Some(path)))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Scope {
+    /// Read access to notes, files, tasks and events, including search.
+    Read,
+    /// Full access except for accounts, sessions and tokens.
+    Write,
+    /// Read and write access to tasks under `.tasks/`.
+    Tasks,
+    /// Uploading files.
+    FilesUpload,
+}
+
+/// What a personal access token is allowed to do.
+#[derive(Debug, Clone)]
+pub struct TokenGrant {
+    pub id: String,
+    pub scopes: Vec<Scope>,
+    pub path_prefix: Option<String>,
+}
+
+impl TokenGrant {
+    /// Check whether a request to `route` (a route pattern like `/notes/*path`) is allowed.
+    ///
+    /// `path` is the repository path the request addresses, if any.
+    pub fn permits(&self, method: &Method, route: &str, path: Option<&str>) -> bool {
+        // Tokens never manage accounts, sessions or tokens
+        if route.starts_with("/logout") || route.starts_with("/v2/tokens") || route.starts_with("/v2/account/") || route.starts_with("/v2/admin/") {
+            return false;
+        }
+
+        if let Some(prefix) = &self.path_prefix {
+            match path {
+                Some(path) if path_has_prefix(path, prefix) => (),
+                Some(_) => return false,
+                // Uploads, commits, the trash, diffs and reverts are checked path by path in the handler
+                None if route == "/files" || route == "/v2/commits" || route == "/v2/commits/head" || route.starts_with("/v2/trash") || route == "/v2/diff" || route == "/v2/revert" => (),
+                None => return false,
+            }
+        }
+
+        let is_read = matches!(*method, Method::GET | Method::HEAD) || (method == Method::POST && route == "/notes");
+        self.scopes.iter().any(|scope| match scope {
+            Scope::Read => is_read,
+            Scope::Write => true,
+            Scope::Tasks => match path {
+                Some(path) => path.starts_with(".tasks/"),
+                None => route == "/v2/tasks" || route == "/v2/commits" || route.starts_with("/v2/trash") || route == "/v2/revert",
+            },
+            Scope::FilesUpload => method == Method::POST && route == "/files",
+        })
+    }
+}
+
+struct FailedAttempts {
+    count: u32,
+    last_failure: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// Failed login attempts per key, such as an account or a client IP address.
+///
+/// After `free_attempts` failures, each further failure locks the key out for an exponentially growing time.
+pub struct LoginThrottle {
+    free_attempts: u32,
+    base_lockout: time::Duration,
+    max_lockout: time::Duration,
+    forget_after: time::Duration,
+    attempts: HashMap<String, FailedAttempts>,
+}
+
+impl LoginThrottle {
+    pub fn from_env() -> Self {
+        let free_attempts = env::var("MORIED_LOGIN_FREE_ATTEMPTS").map_or(5, |v| {
+            v.parse::<u32>().expect("Number of login attempts represented as integer value is expected")
+        });
+        let max_lockout_minutes = env::var("MORIED_LOGIN_MAX_LOCKOUT_MINUTES").map_or(15, |v| {
+            v.parse::<u64>().expect("Lockout duration in minutes represented as integer value is expected")
+        });
+        LoginThrottle {
+            free_attempts,
+            base_lockout: time::Duration::from_secs(1),
+            max_lockout: time::Duration::from_secs(max_lockout_minutes * 60),
+            forget_after: time::Duration::from_secs(60 * 60),
+            attempts: HashMap::new(),
+        }
+    }
+
+    /// Remaining lockout time of the most restricted key, if any.
+    pub fn retry_after(&self, keys: &[String], now: Instant) -> Option<time::Duration> {
+        keys.iter()
+            .filter_map(|key| self.attempts.get(key)?.locked_until)
+            .filter(|&locked_until| locked_until > now)
+            .map(|locked_until| locked_until - now)
+            .max()
+    }
+
+    pub fn record_failure(&mut self, keys: &[String], now: Instant) {
+        // Keep memory bounded under attacks from many addresses
+        if self.attempts.len() > 10_000 {
+            let forget_after = self.forget_after;
+            self.attempts.retain(|_, attempts| now - attempts.last_failure < forget_after);
+        }
+
+        for key in keys {
+            let attempts = self.attempts.entry(key.clone()).or_insert(FailedAttempts {
+                count: 0,
+                last_failure: now,
+                locked_until: None,
+            });
+            if now - attempts.last_failure >= self.forget_after {
+                attempts.count = 0;
+            }
+            attempts.count += 1;
+            attempts.last_failure = now;
+            if attempts.count > self.free_attempts {
+                let exponent = (attempts.count - self.free_attempts - 1).min(20);
+                let lockout = (self.base_lockout * 2u32.pow(exponent)).min(self.max_lockout);
+                attempts.locked_until = Some(now + lockout);
+            }
+        }
+    }
+
+    /// Forget the failures of `key`.
+    pub fn record_success(&mut self, key: &str) {
+        self.attempts.remove(key);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub id: String,
+    pub user: String,
+    pub refresh_token_hash: String,
+    pub expires_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+impl Session {
+    fn from_row(row: SqliteRow) -> Self {
+        Session {
+            id: row.get("id"),
+            user: row.get("user"),
+            refresh_token_hash: row.get("refresh_token_hash"),
+            expires_at: Utc.timestamp_opt(row.get("expires_at"), 0).unwrap(),
+            revoked_at: row.get::<Option<i64>, _>("revoked_at").map(|t| Utc.timestamp_opt(t, 0).unwrap()),
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.revoked_at.is_none() && self.expires_at > Utc::now()
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct ApiToken {
+    pub id: String,
+    #[serde(skip_serializing)]
+    pub user: String,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub path_prefix: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+impl ApiToken {
+    fn from_row(row: SqliteRow) -> Self {
+        ApiToken {
+            id: row.get("id"),
+            user: row.get("user"),
+            name: row.get("name"),
+            scopes: serde_json::from_str(&row.get::<String, _>("scopes")).unwrap(),
+            path_prefix: row.get("path_prefix"),
+            created_at: Utc.timestamp_opt(row.get("created_at"), 0).unwrap(),
+            last_used_at: row.get::<Option<i64>, _>("last_used_at").map(|t| Utc.timestamp_opt(t, 0).unwrap()),
+            revoked_at: row.get::<Option<i64>, _>("revoked_at").map(|t| Utc.timestamp_opt(t, 0).unwrap()),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct NewApiToken {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub path_prefix: Option<RepoPath>,
+}
+
+/// A newly created personal access token, the only time its secret is shown.
+#[derive(Debug, Serialize, Clone)]
+pub struct CreatedApiToken {
+    pub token: String,
+    #[serde(flatten)]
+    pub info: ApiToken,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct TokenPair {
+    pub access_token: String,
+    pub refresh_token: String,
+    pub token_type: String,
+    pub expires_in: i64,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Refresh {
+    pub refresh_token: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Login {
+    pub user: String,
+    pub password: String,
+    /// TOTP code or recovery code, required if the user has enrolled in TOTP.
+    pub otp: Option<String>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct TotpEnrollment {
+    pub secret: String,
+    pub provisioning_uri: String,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct TotpCode {
+    pub code: String,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct RecoveryCodes {
+    pub recovery_codes: Vec<String>,
+}
+
+impl AppState {
+    pub async fn find_session(&self, id: &str) -> Result<Option<Session>> {
+        let session = sqlx::query("SELECT * FROM session WHERE id = ?;")
+            .bind(id)
+            .map(Session::from_row)
+            .fetch_optional(&self.cache_db)
+            .await?;
+        Ok(session)
+    }
+
+    pub async fn find_session_by_refresh_token(&self, refresh_token_hash: &str) -> Result<Option<Session>> {
+        let session = sqlx::query("SELECT * FROM session WHERE refresh_token_hash = ?;")
+            .bind(refresh_token_hash)
+            .map(Session::from_row)
+            .fetch_optional(&self.cache_db)
+            .await?;
+        Ok(session)
+    }
+
+    pub async fn find_api_token_by_hash(&self, token_hash: &str) -> Result<Option<ApiToken>> {
+        let api_token = sqlx::query("SELECT * FROM api_token WHERE token_hash = ?;")
+            .bind(token_hash)
+            .map(ApiToken::from_row)
+            .fetch_optional(&self.cache_db)
+            .await?;
+        Ok(api_token)
+    }
+
+    pub async fn find_api_token(&self, id: &str) -> Result<Option<ApiToken>> {
+        let api_token = sqlx::query("SELECT * FROM api_token WHERE id = ?;")
+            .bind(id)
+            .map(ApiToken::from_row)
+            .fetch_optional(&self.cache_db)
+            .await?;
+        Ok(api_token)
+    }
+
+    pub async fn list_api_tokens(&self, user: &str) -> Result<Vec<ApiToken>> {
+        let api_tokens = sqlx::query("SELECT * FROM api_token WHERE user = ? ORDER BY created_at;")
+            .bind(user)
+            .map(ApiToken::from_row)
+            .fetch_all(&self.cache_db)
+            .await?;
+        Ok(api_tokens)
+    }
+}
+
+/// Prefix of personal access tokens, which distinguishes them from session JWTs.
+const API_TOKEN_PREFIX: &str = "moried_pat_";
+
+pub fn hash_password(password: &str) -> Result<String> {
+    let salt: [u8; 16] = rand::random();
+    argon2::hash_encoded(password.as_bytes(), &salt, &argon2::Config::default())
+        .context("Failed to hash a password")
+}
+
+pub async fn auth(
+    extract::State(state): extract::State<AppState>,
+    matched_path: Option<extract::MatchedPath>,
+    path_params: Option<extract::Path<HashMap<String, String>>>,
+    mut req: Request<Body>,
+    next: Next,
+) -> Result<Response, AppError> {
+    let token = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+        .map(|(_, token)| token.trim())
+        .ok_or(AppError::Unauthorized)?;
+
+    let principal = if token.starts_with(API_TOKEN_PREFIX) {
+        authenticate_api_token(&state, token).await?
+    }
+    else {
+        authenticate_session(&state, token).await?
+    };
+    let principal = principal.ok_or(AppError::Unauthorized)?;
+
+    // Personal access tokens are limited to the routes their scopes allow
+    if let Some(grant) = &principal.token {
+        let route = matched_path.as_ref().map_or(String::new(), |p| relative_route(p.as_str()));
+        // The path is compared percent-decoded and validated, as handlers see it
+        let path = path_params.as_ref().and_then(|params| params.get("path")).and_then(|path| RepoPath::new(path).ok());
+        if !grant.permits(req.method(), &route, path.as_ref().map(RepoPath::as_str)) {
+            tracing::debug!("token {} is not allowed to access {} {}", grant.id, req.method(), route);
+            return Err(AppError::Forbidden);
+        }
+    }
+
+    tracing::debug!("authorized as {}", principal.user);
+    let actor = AuditActor {
+        user: principal.user.clone(),
+        token_id: principal.token.as_ref().map(|grant| grant.id.clone()),
+    };
+    req.extensions_mut().insert(principal);
+    let mut res = next.run(req).await;
+    res.extensions_mut().insert(actor);
+    Ok(res)
+}
+
+async fn authenticate_session(state: &AppState, token: &str) -> Result<Option<Principal>> {
+    let claims = match decode_token(token) {
+        Some(claims) => claims,
+        None => return Ok(None),
+    };
+
+    // The session must not have been revoked
+    match state.find_session(&claims.jti).await? {
+        Some(session) if session.is_active() && session.user == claims.sub => (),
+        _ => {
+            tracing::debug!("revoked or expired session: {}", claims.jti);
+            return Ok(None);
+        },
+    }
+
+    // The account must still exist and be enabled
+    match state.find_user(&claims.sub).await? {
+        Some(user) if !user.disabled => {
+            let acl = state.path_access(&user, None).await?;
+            Ok(Some(Principal {
+                user: user.name,
+                email: user.email,
+                display_name: user.display_name,
+                session_id: Some(claims.jti),
+                token: None,
+                acl,
+            }))
+        },
+        _ => {
+            tracing::debug!("unknown or disabled user: {}", claims.sub);
+            Ok(None)
+        },
+    }
+}
+
+async fn authenticate_api_token(state: &AppState, token: &str) -> Result<Option<Principal>> {
+    let api_token = match state.find_api_token_by_hash(&hash_token(token)).await? {
+        Some(api_token) if api_token.revoked_at.is_none() => api_token,
+        _ => {
+            tracing::debug!("unknown or revoked API token");
+            return Ok(None);
+        },
+    };
+
+    let user = match state.find_user(&api_token.user).await? {
+        Some(user) if !user.disabled => user,
+        _ => {
+            tracing::debug!("unknown or disabled user: {}", api_token.user);
+            return Ok(None);
+        },
+    };
+
+    if let Err(e) = sqlx::query("UPDATE api_token SET last_used_at = ? WHERE id = ?;")
+        .bind(Utc::now().timestamp())
+        .bind(&api_token.id)
+        .execute(&state.cache_db_writer)
+        .await
+    {
+        tracing::warn!("failed to record the use of API token {}: {:?}", api_token.id, e);
+    }
+
+    let acl = state.path_access(&user, Some(&api_token.id)).await?;
+    Ok(Some(Principal {
+        user: user.name,
+        email: user.email,
+        display_name: user.display_name,
+        session_id: None,
+        token: Some(TokenGrant {
+            id: api_token.id,
+            scopes: api_token.scopes,
+            path_prefix: api_token.path_prefix,
+        }),
+        acl,
+    }))
+}
+
+pub async fn require_admin(
+    extract::State(state): extract::State<AppState>,
+    extract::Extension(principal): extract::Extension<Principal>,
+    req: Request<Body>,
+    next: Next,
+) -> Result<Response, AppError> {
+    match state.find_user(&principal.user).await? {
+        Some(user) if user.admin => Ok(next.run(req).await),
+        _ => Err(AppError::Forbidden),
+    }
+}
+
+fn decode_token(token: &str) -> Option<Claims> {
+    let secret = env::var("MORIED_SECRET").unwrap();
+    match jwt::decode::<Claims>(token, &jwt::DecodingKey::from_secret(secret.as_ref()), &jwt::Validation::default()) {
+        Ok(data) => Some(data.claims),
+        Err(e) => {
+            tracing::debug!("failed to decode token: {:?}", e);
+            None
+        },
+    }
+}
+
+fn session_duration() -> Duration {
+    env::var("MORIED_SESSION_EXPIRY_MINUTES").map_or(Duration::hours(6), |v| {
+        Duration::minutes(v.parse::<i64>().expect("Session duration in minutes represented as integer value is expected"))
+    })
+}
+
+fn access_token_duration() -> Duration {
+    env::var("MORIED_ACCESS_TOKEN_EXPIRY_MINUTES").map_or(Duration::minutes(15), |v| {
+        Duration::minutes(v.parse::<i64>().expect("Access token duration in minutes represented as integer value is expected"))
+    })
+}
+
+pub fn random_token() -> String {
+    let bytes: [u8; 32] = rand::random();
+    let mut buf = [0u8; 64];
+    base16ct::lower::encode_str(&bytes, &mut buf).unwrap().to_owned()
+}
+
+fn hash_token(token: &str) -> String {
+    let hash = Sha256::digest(token.as_bytes());
+    let mut buf = [0u8; 64];
+    base16ct::lower::encode_str(&hash, &mut buf).unwrap().to_owned()
+}
+
+/// Hash of a recovery code, keyed with `MORIED_SECRET` as the codes are short enough to guess from a plain hash.
+fn hash_recovery_code(user: &str, code: &str) -> String {
+    let secret = env::var("MORIED_SECRET").unwrap();
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(b"moried recovery code\0");
+    mac.update(user.as_bytes());
+    mac.update(b"\0");
+    mac.update(code.as_bytes());
+    let mut buf = [0u8; 64];
+    base16ct::lower::encode_str(&mac.finalize().into_bytes(), &mut buf).unwrap().to_owned()
+}
+
+/// Issue a short-lived access token for a session.
+fn issue_access_token(user: &User, session_id: &str) -> Result<(String, Duration)> {
+    let secret = env::var("MORIED_SECRET").unwrap();
+    let duration = access_token_duration();
+    let now: DateTime<Utc> = Utc::now();
+    let my_claims = Claims {
+        sub: user.name.clone(),
+        exp: (now + duration).timestamp() as usize,
+        email: user.email.clone(),
+        jti: session_id.to_owned(),
+    };
+    let token = jwt::encode(
+        &jwt::Header::default(),
+        &my_claims,
+        &jwt::EncodingKey::from_secret(secret.as_ref())
+    ).context("Failed to encode an access token")?;
+    Ok((token, duration))
+}
+
+/// Start a new session for `user` and return its first token pair.
+pub async fn start_session(state: &AppState, user: &User) -> Result<TokenPair> {
+    let session_id = uuid::Builder::from_random_bytes(rand::random()).into_uuid().to_string();
+    let refresh_token = random_token();
+    let now = Utc::now();
+
+    // Forget sessions that can no longer be used
+    sqlx::query("DELETE FROM session WHERE expires_at < ?;")
+        .bind(now.timestamp())
+        .execute(&state.cache_db_writer)
+        .await
+        .context("Failed to delete expired sessions")?;
+
+    sqlx::query("INSERT INTO session (id, user, refresh_token_hash, created_at, expires_at) VALUES (?, ?, ?, ?, ?);")
+        .bind(&session_id)
+        .bind(&user.name)
+        .bind(hash_token(&refresh_token))
+        .bind(now.timestamp())
+        .bind((now + session_duration()).timestamp())
+        .execute(&state.cache_db_writer)
+        .await
+        .context("Failed to create a session")?;
+
+    let (access_token, duration) = issue_access_token(user, &session_id)?;
+    Ok(TokenPair {
+        access_token,
+        refresh_token,
+        token_type: "Bearer".to_owned(),
+        expires_in: duration.num_seconds(),
+    })
+}
+
+/// Address of the client, taken from `X-Forwarded-For` when running behind a trusted reverse proxy.
+pub fn client_ip(addr: SocketAddr, headers: &HeaderMap) -> IpAddr {
+    let trust_proxy = env::var("MORIED_TRUST_X_FORWARDED_FOR").is_ok_and(|v| v == "true");
+    if trust_proxy {
+        // The last entry is the one added by the proxy in front of us
+        let forwarded = headers
+            .get("x-forwarded-for")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.rsplit(',').next())
+            .and_then(|ip| ip.trim().parse().ok());
+        if let Some(ip) = forwarded {
+            return ip;
+        }
+    }
+    addr.ip()
+}
+
+pub async fn post_login(
+    extract::State(state): extract::State<AppState>,
+    extract::ConnectInfo(addr): extract::ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    AppJson(login): AppJson<Login>,
+) -> Response {
+    tracing::debug!("post_login");
+    let actor = AuditActor { user: login.user.clone(), token_id: None };
+    let mut res = log_in(&state, client_ip(addr, &headers), login).await.into_response();
+    res.extensions_mut().insert(actor);
+    res
+}
+
+async fn log_in(state: &AppState, ip: IpAddr, login: Login) -> Result<Json<TokenPair>, AppError> {
+    let throttle_keys = [format!("user:{}", login.user), format!("ip:{}", ip)];
+
+    // Refuse early while the account or the client is locked out
+    let retry_after = state.login_throttle.lock().unwrap().retry_after(&throttle_keys, time::Instant::now());
+    if let Some(retry_after) = retry_after {
+        tracing::warn!("Rejected login for '{}' from {}: locked out for {}s", login.user, ip, retry_after.as_secs());
+        return Err(AppError::TooManyRequests { retry_after });
+    }
+
+    // Unknown users are verified against a dummy hash, so that timing does not tell which users exist
+    let user = state.find_user(&login.user).await?;
+    let hash = user.as_ref().map_or_else(|| dummy_hash(), |user| user.hash.as_str());
+    let verified = argon2::verify_encoded(hash, login.password.as_ref()).unwrap_or(false);
+    let user = match user {
+        Some(user) if verified && !user.disabled => user,
+        _ => return Err(failed_login(state, &throttle_keys, &login.user, ip, "wrong user name or password")),
+    };
+
+    // Ask for the second factor if the user has enrolled in TOTP
+    if user.totp_enabled {
+        let otp = login.otp.as_deref().ok_or(AppError::OtpRequired)?;
+        if !check_second_factor(state, &user, otp).await? {
+            return Err(failed_login(state, &throttle_keys, &login.user, ip, "wrong one-time password"));
+        }
+    }
+
+    // Failures from the client are kept, so that logging in to one account does not reset guessing at others
+    state.login_throttle.lock().unwrap().record_success(&throttle_keys[0]);
+
+    Ok(Json(start_session(state, &user).await?))
+}
+
+/// Hash of a random password, with the same cost as the hashes of users.
+fn dummy_hash() -> &'static str {
+    static HASH: OnceLock<String> = OnceLock::new();
+    HASH.get_or_init(|| hash_password(&random_token()).unwrap())
+}
+
+fn failed_login(state: &AppState, throttle_keys: &[String], user: &str, ip: IpAddr, reason: &str) -> AppError {
+    let mut throttle = state.login_throttle.lock().unwrap();
+    let now = time::Instant::now();
+    throttle.record_failure(throttle_keys, now);
+    tracing::warn!("Failed login for '{}' from {}: {}", user, ip, reason);
+    match throttle.retry_after(throttle_keys, now) {
+        Some(retry_after) => {
+            tracing::warn!("Locked out login for '{}' from {} for {}s", user, ip, retry_after.as_secs());
+            AppError::TooManyRequests { retry_after }
+        },
+        None => AppError::Unauthorized,
+    }
+}
+
+/// Verify a TOTP code or consume a recovery code of `user`.
+async fn check_second_factor(state: &AppState, user: &User, code: &str) -> Result<bool> {
+    let code = code.trim();
+
+    if let Some(secret) = &user.totp_secret {
+        if let Some(step) = totp::verify(secret, code, Utc::now().timestamp()) {
+            // A code is accepted only once
+            let result = sqlx::query("UPDATE user SET totp_last_step = ? WHERE name = ? AND (totp_last_step IS NULL OR totp_last_step < ?);")
+                .bind(step)
+                .bind(&user.name)
+                .bind(step)
+                .execute(&state.cache_db_writer)
+                .await
+                .context("Failed to record a TOTP step")?;
+            return Ok(result.rows_affected() > 0);
+        }
+    }
+
+    let result = sqlx::query("UPDATE recovery_code SET used_at = ? WHERE user = ? AND code_hash = ? AND used_at IS NULL;")
+        .bind(Utc::now().timestamp())
+        .bind(&user.name)
+        .bind(hash_recovery_code(&user.name, code))
+        .execute(&state.cache_db_writer)
+        .await
+        .context("Failed to consume a recovery code")?;
+    if result.rows_affected() > 0 {
+        tracing::info!("User '{}' used a recovery code", user.name);
+        return Ok(true);
+    }
+
+    Ok(false)
+}
+
+/// Check the second factor sent to an account endpoint, throttled along with logins to the account.
+async fn check_account_second_factor(state: &AppState, user: &User, ip: IpAddr, code: &str) -> Result<(), AppError> {
+    let throttle_keys = [format!("user:{}", user.name), format!("ip:{}", ip)];
+
+    let retry_after = state.login_throttle.lock().unwrap().retry_after(&throttle_keys, time::Instant::now());
+    if let Some(retry_after) = retry_after {
+        tracing::warn!("Rejected a one-time password of '{}' from {}: locked out for {}s", user.name, ip, retry_after.as_secs());
+        return Err(AppError::TooManyRequests { retry_after });
+    }
+
+    if !check_second_factor(state, user, code).await? {
+        return match failed_login(state, &throttle_keys, &user.name, ip, "wrong one-time password") {
+            AppError::Unauthorized => Err(AppError::BadRequest("wrong one-time password".to_owned())),
+            e => Err(e),
+        };
+    }
+    Ok(())
+}
+
+/// Replace the recovery codes of `user` with new ones.
+async fn regenerate_recovery_codes(state: &AppState, user: &str) -> Result<Vec<String>> {
+    let codes: Vec<String> = (0..10)
+        .map(|_| {
+            let bytes: [u8; 5] = rand::random();
+            let mut buf = [0u8; 10];
+            let hex = base16ct::lower::encode_str(&bytes, &mut buf).unwrap();
+            format!("{}-{}", &hex[..5], &hex[5..])
+        })
+        .collect();
+
+    let mut tx = state.cache_db_writer.begin().await?;
+    sqlx::query("DELETE FROM recovery_code WHERE user = ?;")
+        .bind(user)
+        .execute(&mut *tx)
+        .await
+        .context("Failed to delete recovery codes")?;
+    for code in &codes {
+        sqlx::query("INSERT INTO recovery_code (user, code_hash) VALUES (?, ?);")
+            .bind(user)
+            .bind(hash_recovery_code(user, code))
+            .execute(&mut *tx)
+            .await
+            .context("Failed to insert a recovery code")?;
+    }
+    tx.commit().await?;
+
+    Ok(codes)
+}
+
+/// Exchange a refresh token for a new token pair.
+///
+/// The refresh token is rotated, so each one can be used only once.
+pub async fn post_refresh(
+    extract::State(state): extract::State<AppState>,
+    AppJson(refresh): AppJson<Refresh>,
+) -> Result<Response, AppError> {
+    tracing::debug!("post_refresh");
+
+    let session = match state.find_session_by_refresh_token(&hash_token(&refresh.refresh_token)).await? {
+        Some(session) if session.is_active() => session,
+        _ => return Err(AppError::Unauthorized),
+    };
+    let user = match state.find_user(&session.user).await? {
+        Some(user) if !user.disabled => user,
+        _ => return Err(AppError::Unauthorized),
+    };
+
+    let refresh_token = random_token();
+    let result = sqlx::query("UPDATE session SET refresh_token_hash = ? WHERE id = ? AND refresh_token_hash = ?;")
+        .bind(hash_token(&refresh_token))
+        .bind(&session.id)
+        .bind(&session.refresh_token_hash)
+        .execute(&state.cache_db_writer)
+        .await
+        .context("Failed to rotate a refresh token")?;
+    if result.rows_affected() == 0 {
+        // The refresh token has been used concurrently
+        return Err(AppError::Unauthorized);
+    }
+
+    let (access_token, duration) = issue_access_token(&user, &session.id)?;
+    Ok((
+        Extension(AuditActor { user: user.name, token_id: None }),
+        Json(TokenPair {
+            access_token,
+            refresh_token,
+            token_type: "Bearer".to_owned(),
+            expires_in: duration.num_seconds(),
+        }),
+    ).into_response())
+}
+
+/// Revoke the session of the current access token.
+pub async fn post_logout(
+    extract::State(state): extract::State<AppState>,
+    extract::Extension(principal): extract::Extension<Principal>,
+) -> Result<Json<bool>, AppError> {
+    tracing::debug!("post_logout");
+    sqlx::query("UPDATE session SET revoked_at = ? WHERE id = ? AND revoked_at IS NULL;")
+        .bind(Utc::now().timestamp())
+        .bind(&principal.session_id)
+        .execute(&state.cache_db_writer)
+        .await
+        .context("Failed to revoke a session")?;
+    Ok(Json(true))
+}
+
+/// Revoke all the sessions of the current user.
+pub async fn post_logout_all(
+    extract::State(state): extract::State<AppState>,
+    extract::Extension(principal): extract::Extension<Principal>,
+) -> Result<Json<bool>, AppError> {
+    tracing::debug!("post_logout_all");
+    let result = sqlx::query("UPDATE session SET revoked_at = ? WHERE user = ? AND revoked_at IS NULL;")
+        .bind(Utc::now().timestamp())
+        .bind(&principal.user)
+        .execute(&state.cache_db_writer)
+        .await
+        .context("Failed to revoke sessions")?;
+    tracing::info!("Revoked {} sessions of user '{}'", result.rows_affected(), principal.user);
+    Ok(Json(true))
+}
+
+pub async fn get_tokens(
+    extract::State(state): extract::State<AppState>,
+    extract::Extension(principal): extract::Extension<Principal>,
+) -> Result<Json<Vec<ApiToken>>, AppError> {
+    tracing::debug!("auth::get_tokens");
+    Ok(Json(state.list_api_tokens(&principal.user).await?))
+}
+
+pub async fn post_tokens(
+    extract::State(state): extract::State<AppState>,
+    extract::Extension(principal): extract::Extension<Principal>,
+    AppJson(new_token): AppJson<NewApiToken>,
+) -> Result<Response, AppError> {
+    tracing::debug!("auth::post_tokens");
+
+    if new_token.name.is_empty() || new_token.scopes.is_empty() {
+        return Err(AppError::BadRequest("a name and at least one scope are required".to_owned()));
+    }
+
+    let id = uuid::Builder::from_random_bytes(rand::random()).into_uuid().to_string();
+    let token = format!("{}{}", API_TOKEN_PREFIX, random_token());
+    sqlx::query("INSERT INTO api_token (id, user, name, token_hash, scopes, path_prefix, created_at) VALUES (?, ?, ?, ?, ?, ?, ?);")
+        .bind(&id)
+        .bind(&principal.user)
+        .bind(&new_token.name)
+        .bind(hash_token(&token))
+        .bind(serde_json::to_string(&new_token.scopes)?)
+        .bind(new_token.path_prefix.as_ref().map(RepoPath::as_str))
+        .bind(Utc::now().timestamp())
+        .execute(&state.cache_db_writer)
+        .await
+        .context("Failed to create an API token")?;
+    tracing::info!("Created API token '{}' ({}) for user '{}'", new_token.name, id, principal.user);
+
+    let info = state.find_api_token(&id).await?
+        .context("Created API token should exist")?;
+    Ok((StatusCode::CREATED, Json(CreatedApiToken { token, info })).into_response())
+}
+
+pub async fn delete_tokens_id(
+    AppPath(id): AppPath<String>,
+    extract::State(state): extract::State<AppState>,
+    extract::Extension(principal): extract::Extension<Principal>,
+) -> Result<Response, AppError> {
+    tracing::debug!("auth::delete_tokens_id");
+
+    let result = sqlx::query("UPDATE api_token SET revoked_at = ? WHERE id = ? AND user = ? AND revoked_at IS NULL;")
+        .bind(Utc::now().timestamp())
+        .bind(&id)
+        .bind(&principal.user)
+        .execute(&state.cache_db_writer)
+        .await
+        .context("Failed to revoke an API token")?;
+    if result.rows_affected() == 0 {
+        return Err(AppError::NotFound);
+    }
+    tracing::info!("Revoked API token {} of user '{}'", id, principal.user);
+    Ok(Json(&true).into_response())
+}
+
+/// Start TOTP enrollment; it takes effect once confirmed with a code.
+pub async fn post_account_totp(
+    extract::State(state): extract::State<AppState>,
+    extract::Extension(principal): extract::Extension<Principal>,
+) -> Result<Response, AppError> {
+    tracing::debug!("auth::post_account_totp");
+
+    let user = state.find_user(&principal.user).await?
+        .context("Authenticated user should exist")?;
+    if user.totp_enabled {
+        return Err(AppError::conflict("TOTP is already enabled", ()));
+    }
+
+    let secret = totp::generate_secret();
+    sqlx::query("UPDATE user SET totp_secret = ?, totp_last_step = NULL WHERE name = ?;")
+        .bind(&secret)
+        .bind(&user.name)
+        .execute(&state.cache_db_writer)
+        .await
+        .context("Failed to store a TOTP secret")?;
+
+    let issuer = env::var("MORIED_TOTP_ISSUER").unwrap_or_else(|_| "moried".to_owned());
+    let provisioning_uri = totp::provisioning_uri(&secret, &user.name, &issuer);
+    Ok(Json(TotpEnrollment { secret, provisioning_uri }).into_response())
+}
+
+pub async fn post_account_totp_confirm(
+    extract::State(state): extract::State<AppState>,
+    extract::Extension(principal): extract::Extension<Principal>,
+    AppJson(totp_code): AppJson<TotpCode>,
+) -> Result<Response, AppError> {
+    tracing::debug!("auth::post_account_totp_confirm");
+
+    let user = state.find_user(&principal.user).await?
+        .context("Authenticated user should exist")?;
+    if user.totp_enabled {
+        return Err(AppError::conflict("TOTP is already enabled", ()));
+    }
+    let step = match user.totp_secret.as_deref().and_then(|secret| totp::verify(secret, totp_code.code.trim(), Utc::now().timestamp())) {
+        Some(step) => step,
+        None => return Err(AppError::BadRequest("wrong one-time password".to_owned())),
+    };
+
+    sqlx::query("UPDATE user SET totp_enabled = 1, totp_last_step = ? WHERE name = ?;")
+        .bind(step)
+        .bind(&user.name)
+        .execute(&state.cache_db_writer)
+        .await
+        .context("Failed to enable TOTP")?;
+    tracing::info!("Enabled TOTP for user '{}'", user.name);
+
+    let recovery_codes = regenerate_recovery_codes(&state, &user.name).await?;
+    Ok(Json(RecoveryCodes { recovery_codes }).into_response())
+}
+
+pub async fn post_account_totp_disable(
+    extract::State(state): extract::State<AppState>,
+    extract::ConnectInfo(addr): extract::ConnectInfo<SocketAddr>,
+    extract::Extension(principal): extract::Extension<Principal>,
+    headers: HeaderMap,
+    AppJson(totp_code): AppJson<TotpCode>,
+) -> Result<Response, AppError> {
+    tracing::debug!("auth::post_account_totp_disable");
+
+    let user = state.find_user(&principal.user).await?
+        .context("Authenticated user should exist")?;
+    if !user.totp_enabled {
+        return Err(AppError::conflict("TOTP is not enabled", ()));
+    }
+    check_account_second_factor(&state, &user, client_ip(addr, &headers), &totp_code.code).await?;
+
+    disable_totp(&state, &user.name).await?;
+    tracing::info!("Disabled TOTP for user '{}'", user.name);
+
+    Ok(Json(&true).into_response())
+}
+
+/// Disable TOTP of an account that lost its authenticator, without a one-time password.
+pub async fn post_admin_users_totp_disable(
+    AppPath(name): AppPath<String>,
+    extract::State(state): extract::State<AppState>,
+) -> Result<Response, AppError> {
+    tracing::debug!("auth::post_admin_users_totp_disable");
+
+    if !disable_totp(&state, &name).await? {
+        return Err(AppError::NotFound);
+    }
+    tracing::info!("Reset TOTP of user '{}'", name);
+
+    Ok(Json(&true).into_response())
+}
+
+/// Forget the TOTP secret and recovery codes of an account, and return whether the account exists.
+async fn disable_totp(state: &AppState, name: &str) -> Result<bool> {
+    let result = sqlx::query("UPDATE user SET totp_enabled = 0, totp_secret = NULL, totp_last_step = NULL WHERE name = ?;")
+        .bind(name)
+        .execute(&state.cache_db_writer)
+        .await
+        .context("Failed to disable TOTP")?;
+    sqlx::query("DELETE FROM recovery_code WHERE user = ?;")
+        .bind(name)
+        .execute(&state.cache_db_writer)
+        .await
+        .context("Failed to delete recovery codes")?;
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn post_account_totp_recovery_codes(
+    extract::State(state): extract::State<AppState>,
+    extract::ConnectInfo(addr): extract::ConnectInfo<SocketAddr>,
+    extract::Extension(principal): extract::Extension<Principal>,
+    headers: HeaderMap,
+    AppJson(totp_code): AppJson<TotpCode>,
+) -> Result<Response, AppError> {
+    tracing::debug!("auth::post_account_totp_recovery_codes");
+
+    let user = state.find_user(&principal.user).await?
+        .context("Authenticated user should exist")?;
+    if !user.totp_enabled {
+        return Err(AppError::conflict("TOTP is not enabled", ()));
+    }
+    check_account_second_factor(&state, &user, client_ip(addr, &headers), &totp_code.code).await?;
+
+    let recovery_codes = regenerate_recovery_codes(&state, &user.name).await?;
+    Ok(Json(RecoveryCodes { recovery_codes }).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn throttle() -> LoginThrottle {
+        LoginThrottle {
+            free_attempts: 2,
+            base_lockout: time::Duration::from_secs(1),
+            max_lockout: time::Duration::from_secs(4),
+            forget_after: time::Duration::from_secs(60),
+            attempts: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn throttle_locks_out_after_free_attempts() {
+        let mut throttle = throttle();
+        let keys = ["user:alice".to_string()];
+        let now = Instant::now();
+        throttle.record_failure(&keys, now);
+        throttle.record_failure(&keys, now);
+        assert_eq!(throttle.retry_after(&keys, now), None);
+        // The lockout doubles with every further failure, up to the maximum
+        throttle.record_failure(&keys, now);
+        assert_eq!(throttle.retry_after(&keys, now), Some(time::Duration::from_secs(1)));
+        throttle.record_failure(&keys, now);
+        assert_eq!(throttle.retry_after(&keys, now), Some(time::Duration::from_secs(2)));
+        throttle.record_failure(&keys, now);
+        throttle.record_failure(&keys, now);
+        assert_eq!(throttle.retry_after(&keys, now), Some(time::Duration::from_secs(4)));
+        assert_eq!(throttle.retry_after(&keys, now + time::Duration::from_secs(4)), None);
+    }
+
+    #[test]
+    fn throttle_forgets_old_failures() {
+        let mut throttle = throttle();
+        let keys = ["user:alice".to_string()];
+        let now = Instant::now();
+        throttle.record_failure(&keys, now);
+        throttle.record_failure(&keys, now);
+        let later = now + time::Duration::from_secs(60);
+        throttle.record_failure(&keys, later);
+        assert_eq!(throttle.retry_after(&keys, later), None);
+    }
+
+    #[test]
+    fn throttle_reports_the_most_restricted_key() {
+        let mut throttle = throttle();
+        let now = Instant::now();
+        for _ in 0..4 {
+            throttle.record_failure(&["ip:192.0.2.1".to_string()], now);
+        }
+        let keys = ["user:alice".to_string(), "ip:192.0.2.1".to_string()];
+        throttle.record_failure(&keys, now);
+        assert_eq!(throttle.retry_after(&keys, now), Some(time::Duration::from_secs(4)));
+        // Success clears only the given key
+        throttle.record_success("user:alice");
+        assert_eq!(throttle.retry_after(&keys, now), Some(time::Duration::from_secs(4)));
+    }
+
+    fn login(user: &str, password: &str) -> Login {
+        Login { user: user.to_string(), password: password.to_string(), otp: None }
+    }
+
+    #[tokio::test]
+    async fn successful_login_keeps_failures_of_the_client() {
+        let (mut state, _dir) = testing::app_state().await;
+        state.login_throttle = Arc::new(Mutex::new(throttle()));
+        testing::add_user(&state, "alice", "alice@example.com", "password").await;
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+
+        // Unknown users count as failures too
+        assert!(matches!(log_in(&state, ip, login("nobody", "guess")).await, Err(AppError::Unauthorized)));
+        assert!(matches!(log_in(&state, ip, login("alice", "guess")).await, Err(AppError::Unauthorized)));
+        assert!(log_in(&state, ip, login("alice", "password")).await.is_ok());
+        assert!(matches!(log_in(&state, ip, login("bob", "guess")).await, Err(AppError::TooManyRequests { .. })));
+        // Another client can still log in to the account
+        assert!(log_in(&state, "192.0.2.2".parse().unwrap(), login("alice", "password")).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn wrong_codes_to_account_endpoints_are_throttled() {
+        let (mut state, _dir) = testing::app_state().await;
+        state.login_throttle = Arc::new(Mutex::new(throttle()));
+        testing::add_user(&state, "alice", "alice@example.com", "password").await;
+        sqlx::query("UPDATE user SET totp_secret = ?, totp_enabled = 1 WHERE name = 'alice';")
+            .bind(totp::generate_secret())
+            .execute(&state.cache_db_writer)
+            .await
+            .unwrap();
+        let codes = regenerate_recovery_codes(&state, "alice").await.unwrap();
+        let stored: String = sqlx::query_scalar("SELECT code_hash FROM recovery_code WHERE user = 'alice' LIMIT 1;")
+            .fetch_one(&state.cache_db)
+            .await
+            .unwrap();
+        assert!(!codes.iter().any(|code| hash_token(code) == stored));
+
+        let disable = |code: &str| post_account_totp_disable(
+            extract::State(state.clone()),
+            extract::ConnectInfo("192.0.2.1:1234".parse().unwrap()),
+            extract::Extension(testing::principal("alice")),
+            HeaderMap::new(),
+            AppJson(TotpCode { code: code.to_string() }),
+        );
+        assert!(matches!(disable("00000-00000").await, Err(AppError::BadRequest(_))));
+        assert!(matches!(disable("00000-00000").await, Err(AppError::BadRequest(_))));
+        assert!(matches!(disable("00000-00000").await, Err(AppError::TooManyRequests { .. })));
+        // Even a right code waits for the lockout, which applies to logins too
+        assert!(matches!(disable(&codes[0]).await, Err(AppError::TooManyRequests { .. })));
+        assert!(matches!(log_in(&state, "192.0.2.2".parse().unwrap(), login("alice", "password")).await, Err(AppError::TooManyRequests { .. })));
+
+        state.login_throttle.lock().unwrap().record_success("user:alice");
+        state.login_throttle.lock().unwrap().record_success("ip:192.0.2.1");
+        assert!(disable(&codes[0]).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn token_path_prefix_applies_to_decoded_paths() {
+        let (state, _dir) = testing::app_state().await;
+        testing::add_user(&state, "alice", "alice@example.com", "password").await;
+
+        let invalid = serde_json::json!({"name": "t", "scopes": ["read"], "path_prefix": "../private"});
+        assert!(serde_json::from_value::<NewApiToken>(invalid).is_err());
+
+        let new_token = serde_json::from_value(serde_json::json!({"name": "t", "scopes": ["read"], "path_prefix": "private/"})).unwrap();
+        let response = post_tokens(extract::State(state.clone()), extract::Extension(testing::principal("alice")), AppJson(new_token)).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let created: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(created["path_prefix"], "private");
+        let token = created["token"].as_str().unwrap().to_owned();
+
+        let app = Router::new()
+            .route("/notes/*path", get(|| async { "ok" }))
+            .route_layer(middleware::from_fn_with_state(state.clone(), auth));
+        let status = |uri: &str| {
+            let request = Request::get(uri).header(header::AUTHORIZATION, format!("Bearer {}", token)).body(Body::empty()).unwrap();
+            let mut app = app.clone();
+            async move { tower::Service::call(&mut app, request).await.unwrap().status() }
+        };
+        assert_eq!(status("/notes/priv%61te/x.md").await, StatusCode::OK);
+        assert_eq!(status("/notes/public/x.md").await, StatusCode::FORBIDDEN);
+        assert_eq!(status("/notes/private%2F..%2Fpublic/x.md").await, StatusCode::FORBIDDEN);
+    }
+}
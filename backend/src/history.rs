@@ -0,0 +1,608 @@
+//! History of files in the repository, differences between its revisions, and blame.
+
+use super::*;
+use chrono::{FixedOffset, offset::TimeZone};
+
+pub fn git_time(time: git2::Time) -> DateTime<FixedOffset> {
+    let tz = FixedOffset::east_opt(time.offset_minutes() * 60).unwrap_or(FixedOffset::east_opt(0).unwrap());
+    tz.timestamp_opt(time.seconds(), 0).unwrap()
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct CommitInfo {
+    pub commit_id: String,
+    pub author: String,
+    pub author_email: String,
+    pub time: DateTime<FixedOffset>,
+    pub message: String,
+}
+
+impl CommitInfo {
+    pub fn from_commit(commit: &git2::Commit) -> Self {
+        let author = commit.author();
+        CommitInfo {
+            commit_id: commit.id().to_string(),
+            author: String::from_utf8_lossy(author.name_bytes()).into_owned(),
+            author_email: String::from_utf8_lossy(author.email_bytes()).into_owned(),
+            time: git_time(author.when()),
+            message: String::from_utf8_lossy(commit.message_bytes()).into_owned(),
+        }
+    }
+}
+
+/// A commit that changed a file.
+#[derive(Debug, Serialize, Clone)]
+pub struct HistoryEntry {
+    #[serde(flatten)]
+    pub commit: CommitInfo,
+    /// The path of the file at the commit, which differs from the requested one before a rename.
+    pub path: String,
+    /// The path of the file before the commit if the commit renamed it.
+    pub renamed_from: Option<String>,
+    /// Numbers of lines added and removed.
+    pub added: usize,
+    pub removed: usize,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct HistoryQuery {
+    /// At most 500, defaults to 50.
+    pub limit: Option<u32>,
+    pub offset: Option<u32>,
+}
+
+/// Blob at `path` in `tree`, if any.
+fn blob_at(tree: Option<&git2::Tree>, path: &Path) -> Option<Oid> {
+    let entry = tree?.get_path(path).ok()?;
+    (entry.kind() == Some(git2::ObjectType::Blob)).then(|| entry.id())
+}
+
+/// Numbers of lines added and removed between two versions of a file.
+fn count_changed_lines(repo: &Repository, old: Option<Oid>, new: Option<Oid>) -> Result<(usize, usize), git2::Error> {
+    let old = old.map(|oid| repo.find_blob(oid)).transpose()?;
+    let new = new.map(|oid| repo.find_blob(oid)).transpose()?;
+    let old_content = old.as_ref().map_or(&[][..], |blob| blob.content());
+    let new_content = new.as_ref().map_or(&[][..], |blob| blob.content());
+    let patch = git2::Patch::from_buffers(old_content, None, new_content, None, None)?;
+    let (_, added, removed) = patch.line_stats()?;
+    Ok((added, removed))
+}
+
+/// Commits reachable from `tip` but not from `hide`, newest first.
+///
+/// With `first_parent`, merges are followed only to their first parent, as in the history of a branch.
+pub fn walk_commits<'r>(
+    repo: &'r Repository,
+    tip: Oid,
+    hide: Option<Oid>,
+    first_parent: bool,
+) -> Result<impl Iterator<Item = Result<git2::Commit<'r>, git2::Error>> + 'r, git2::Error> {
+    let mut revwalk = repo.revwalk()?;
+    revwalk.set_sorting(git2::Sort::TOPOLOGICAL)?;
+    revwalk.push(tip)?;
+    if let Some(hide) = hide {
+        revwalk.hide(hide)?;
+    }
+    if first_parent {
+        revwalk.simplify_first_parent()?;
+    }
+    Ok(revwalk.map(move |oid| repo.find_commit(oid?)))
+}
+
+/// Changes from `old` to `new`, where a file moved to another path is a rename.
+pub fn tree_changes<'r>(
+    repo: &'r Repository,
+    old: Option<&git2::Tree>,
+    new: &git2::Tree,
+) -> Result<git2::Diff<'r>, git2::Error> {
+    let mut diff = repo.diff_tree_to_tree(old, Some(new), None)?;
+    diff.find_similar(Some(git2::DiffFindOptions::new().renames(true)))?;
+    Ok(diff)
+}
+
+/// Commits on the first-parent history of HEAD that changed `path`, newest first.
+///
+/// The history follows the file across renames from paths `acl` can read, and skips the first `offset` commits.
+fn collect_file_history(
+    repo: &Repository,
+    acl: &PathAccess,
+    path: &str,
+    offset: usize,
+    limit: usize,
+) -> Result<Vec<HistoryEntry>, git2::Error> {
+    let Some(head_commit) = head_commit(repo)? else {
+        return Ok(Vec::new());
+    };
+    if limit == 0 {
+        return Ok(Vec::new());
+    }
+
+    let mut history = Vec::new();
+    let mut skipped = 0;
+    let mut path = PathBuf::from(path);
+    for commit in walk_commits(repo, head_commit.id(), None, true)? {
+        let commit = commit?;
+        let tree = commit.tree()?;
+        let parent_tree = match commit.parent(0) {
+            Ok(parent) => Some(parent.tree()?),
+            Err(_) => None,
+        };
+
+        let new_blob = blob_at(Some(&tree), &path);
+        let mut old_blob = blob_at(parent_tree.as_ref(), &path);
+        if old_blob == new_blob {
+            continue;
+        }
+
+        // A file added by the commit may have been renamed from another path, which is followed only if
+        // readable, so that the history does not tell about files the user cannot see
+        let mut renamed_from = None;
+        if old_blob.is_none() {
+            let diff = tree_changes(repo, parent_tree.as_ref(), &tree)?;
+            let renamed = diff.deltas()
+                .find(|delta| delta.status() == git2::Delta::Renamed && delta.new_file().path() == Some(&path))
+                .filter(|delta| delta.old_file().path().and_then(Path::to_str).is_some_and(|from| acl.can_read(from)));
+            if let Some(delta) = renamed {
+                old_blob = Some(delta.old_file().id());
+                renamed_from = delta.old_file().path().map(Path::to_path_buf);
+            }
+        }
+
+        if skipped < offset {
+            skipped += 1;
+        }
+        else {
+            let (added, removed) = count_changed_lines(repo, old_blob, new_blob)?;
+            history.push(HistoryEntry {
+                commit: CommitInfo::from_commit(&commit),
+                path: path.to_string_lossy().into_owned(),
+                renamed_from: renamed_from.as_ref().map(|from| from.to_string_lossy().into_owned()),
+                added,
+                removed,
+            });
+            if history.len() >= limit {
+                break;
+            }
+        }
+
+        // Older commits know the file by its previous path
+        if let Some(from) = renamed_from {
+            path = from;
+        }
+    }
+    Ok(history)
+}
+
+pub async fn get_history_path(
+    AppPath(path): AppPath<String>,
+    AppQuery(query): AppQuery<HistoryQuery>,
+    extract::State(state): extract::State<AppState>,
+    extract::Extension(principal): extract::Extension<Principal>,
+) -> Result<Json<Vec<HistoryEntry>>, AppError> {
+    tracing::debug!("history::get_history_path");
+
+    if !principal.acl.can_read(&path) {
+        return Err(AppError::NotFound);
+    }
+
+    let limit = query.limit.unwrap_or(50).min(500) as usize;
+    let offset = query.offset.unwrap_or(0) as usize;
+    let repo = state.repo.lock().unwrap();
+    Ok(Json(collect_file_history(&repo, &principal.acl, &path, offset, limit)?))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DiffMode {
+    Line,
+    /// Also compare the text of each hunk word by word.
+    Word,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct DiffQuery {
+    /// Defaults to the parent of `to`.
+    pub from: Option<String>,
+    /// Defaults to HEAD.
+    pub to: Option<String>,
+    pub mode: Option<DiffMode>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct Diff {
+    /// `None` when diffing the root commit against an empty tree.
+    pub from: Option<String>,
+    pub to: String,
+    pub files: Vec<FileDiff>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChangeStatus {
+    Added,
+    Modified,
+    Deleted,
+    Renamed,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct FileDiff {
+    pub path: String,
+    /// The path before a rename.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub old_path: Option<String>,
+    pub status: ChangeStatus,
+    pub binary: bool,
+    pub added: usize,
+    pub removed: usize,
+    /// Only for the file named in the request.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hunks: Option<Vec<DiffHunk>>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct DiffHunk {
+    pub old_start: u32,
+    pub old_lines: u32,
+    pub new_start: u32,
+    pub new_lines: u32,
+    pub lines: Vec<DiffLine>,
+    /// The text of the hunk as unchanged, added and removed runs of words, in `word` mode.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub words: Option<Vec<DiffSegment>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LineKind {
+    Context,
+    Added,
+    Removed,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct DiffLine {
+    pub kind: LineKind,
+    /// Line numbers in the old and new file, from 1.
+    pub old_line: Option<u32>,
+    pub new_line: Option<u32>,
+    /// Without the line break.
+    pub content: String,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct DiffSegment {
+    pub kind: LineKind,
+    pub text: String,
+}
+
+/// Files changed between two trees, with the hunks of those that are exactly `path`.
+///
+/// Only files under `path` that pass `can_read` are listed, by both paths in the case of a rename.
+fn collect_diff(
+    repo: &Repository,
+    old_tree: Option<&git2::Tree>,
+    new_tree: &git2::Tree,
+    path: &str,
+    mode: DiffMode,
+    can_read: impl Fn(&str) -> bool,
+) -> Result<Vec<FileDiff>, git2::Error> {
+    use git2::Delta;
+
+    let diff = tree_changes(repo, old_tree, new_tree)?;
+
+    let mut files = Vec::new();
+    for (i, delta) in diff.deltas().enumerate() {
+        let status = match delta.status() {
+            Delta::Added => ChangeStatus::Added,
+            Delta::Deleted => ChangeStatus::Deleted,
+            Delta::Renamed => ChangeStatus::Renamed,
+            _ => ChangeStatus::Modified,
+        };
+        let old_path = (status != ChangeStatus::Added).then(|| delta.old_file().path()).flatten().map(|path| path.to_string_lossy().into_owned());
+        let new_path = (status != ChangeStatus::Deleted).then(|| delta.new_file().path()).flatten().map(|path| path.to_string_lossy().into_owned());
+        let paths = || old_path.iter().chain(new_path.iter());
+        if !paths().any(|p| path_has_prefix(p, path)) || !paths().all(|p| can_read(p)) {
+            continue;
+        }
+
+        let Some(patch) = git2::Patch::from_diff(&diff, i)? else {
+            continue;
+        };
+        let (_, added, removed) = patch.line_stats()?;
+        let binary = patch.delta().flags().is_binary();
+        let hunks = if !binary && paths().any(|p| p == path) {
+            Some(collect_hunks(&patch, mode)?)
+        }
+        else {
+            None
+        };
+        files.push(FileDiff {
+            path: new_path.clone().or_else(|| old_path.clone()).unwrap_or_default(),
+            old_path: if status == ChangeStatus::Renamed { old_path } else { None },
+            status,
+            binary,
+            added,
+            removed,
+            hunks,
+        });
+    }
+    Ok(files)
+}
+
+/// Hunks of a patch, with the changes within each by word in `DiffMode::Word`.
+fn collect_hunks(patch: &git2::Patch, mode: DiffMode) -> Result<Vec<DiffHunk>, git2::Error> {
+    let mut hunks = Vec::with_capacity(patch.num_hunks());
+    for hunk_idx in 0..patch.num_hunks() {
+        let (hunk, num_lines) = patch.hunk(hunk_idx)?;
+        let mut lines = Vec::with_capacity(num_lines);
+        for line_idx in 0..num_lines {
+            let line = patch.line_in_hunk(hunk_idx, line_idx)?;
+            let kind = match line.origin() {
+                ' ' => LineKind::Context,
+                '+' => LineKind::Added,
+                '-' => LineKind::Removed,
+                // Markers for a missing newline at the end of file
+                _ => continue,
+            };
+            let content = String::from_utf8_lossy(line.content());
+            lines.push(DiffLine {
+                kind,
+                old_line: line.old_lineno(),
+                new_line: line.new_lineno(),
+                content: content.strip_suffix('\n').unwrap_or(&content).to_owned(),
+            });
+        }
+
+        let words = (mode == DiffMode::Word).then(|| diff_words(&lines));
+        hunks.push(DiffHunk {
+            old_start: hunk.old_start(),
+            old_lines: hunk.old_lines(),
+            new_start: hunk.new_start(),
+            new_lines: hunk.new_lines(),
+            lines,
+            words,
+        });
+    }
+    Ok(hunks)
+}
+
+/// Changes by word between the old and new text of a hunk, merging runs of the same kind.
+fn diff_words(lines: &[DiffLine]) -> Vec<DiffSegment> {
+    let text = |kind: LineKind| {
+        lines.iter()
+            .filter(|line| line.kind == LineKind::Context || line.kind == kind)
+            .map(|line| format!("{}\n", line.content))
+            .collect::<String>()
+    };
+    let old_text = text(LineKind::Removed);
+    let new_text = text(LineKind::Added);
+
+    let mut segments: Vec<DiffSegment> = Vec::new();
+    for change in similar::TextDiff::from_words(&old_text, &new_text).iter_all_changes() {
+        let kind = match change.tag() {
+            similar::ChangeTag::Equal => LineKind::Context,
+            similar::ChangeTag::Insert => LineKind::Added,
+            similar::ChangeTag::Delete => LineKind::Removed,
+        };
+        match segments.last_mut() {
+            Some(last) if last.kind == kind => last.text.push_str(change.value()),
+            _ => segments.push(DiffSegment { kind, text: change.value().to_owned() }),
+        }
+    }
+    segments
+}
+
+pub async fn get_diff(
+    AppQuery(query): AppQuery<DiffQuery>,
+    extract::State(state): extract::State<AppState>,
+    extract::Extension(principal): extract::Extension<Principal>,
+) -> Result<Json<Diff>, AppError> {
+    tracing::debug!("history::get_diff");
+    make_diff("", query, state, principal)
+}
+
+pub async fn get_diff_path(
+    AppPath(path): AppPath<String>,
+    AppQuery(query): AppQuery<DiffQuery>,
+    extract::State(state): extract::State<AppState>,
+    extract::Extension(principal): extract::Extension<Principal>,
+) -> Result<Json<Diff>, AppError> {
+    tracing::debug!("history::get_diff_path");
+
+    if !principal.acl.can_read(&path) {
+        return Err(AppError::NotFound);
+    }
+    make_diff(&path, query, state, principal)
+}
+
+fn make_diff(
+    path: &str,
+    query: DiffQuery,
+    state: AppState,
+    principal: Principal,
+) -> Result<Json<Diff>, AppError> {
+    let repo = state.repo.lock().unwrap();
+    let to_commit = match &query.to {
+        Some(rev) => repo.find_commit(resolve_revision(&repo, rev)?)?,
+        None => head_commit(&repo)?.ok_or(AppError::NotFound)?,
+    };
+    // Defaults to the changes made by `to`
+    let from_commit = match &query.from {
+        Some(rev) => Some(repo.find_commit(resolve_revision(&repo, rev)?)?),
+        None => to_commit.parent(0).ok(),
+    };
+    let old_tree = from_commit.as_ref().map(|commit| commit.tree()).transpose()?;
+    let new_tree = to_commit.tree()?;
+
+    // A path must exist on either side
+    if !path.is_empty() && [old_tree.as_ref(), Some(&new_tree)].iter().all(|tree| tree.is_none_or(|tree| tree.get_path(path.as_ref()).is_err())) {
+        return Err(AppError::NotFound);
+    }
+
+    let can_read = |path: &str| principal.permits(&Method::GET, "/v2/diff/*path", path) && principal.acl.can_read(path);
+    let files = collect_diff(&repo, old_tree.as_ref(), &new_tree, path, query.mode.unwrap_or(DiffMode::Line), can_read)?;
+    Ok(Json(Diff {
+        from: from_commit.map(|commit| commit.id().to_string()),
+        to: to_commit.id().to_string(),
+        files,
+    }))
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct BlameQuery {
+    /// Defaults to HEAD.
+    pub rev: Option<String>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct Blame {
+    /// The commit the file is read at.
+    pub commit_id: String,
+    pub ranges: Vec<BlameRange>,
+}
+
+/// Consecutive lines last changed by the same commit.
+#[derive(Debug, Serialize, Clone)]
+pub struct BlameRange {
+    /// From 1.
+    pub start_line: usize,
+    pub lines: usize,
+    #[serde(flatten)]
+    pub commit: CommitInfo,
+    /// The path of the file in that commit if it has been renamed since.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+}
+
+/// Ranges of lines of `path` at a commit, each with the commit that last changed them.
+fn collect_blame(repo: &Repository, path: &str, commit_id: Oid) -> Result<Vec<BlameRange>, git2::Error> {
+    let mut options = git2::BlameOptions::new();
+    options.newest_commit(commit_id).first_parent(true);
+    let blame = repo.blame_file(Path::new(path), Some(&mut options))?;
+
+    let mut commits: HashMap<Oid, CommitInfo> = HashMap::new();
+    let mut ranges = Vec::with_capacity(blame.len());
+    for hunk in blame.iter() {
+        let commit_id = hunk.final_commit_id();
+        let commit = match commits.get(&commit_id) {
+            Some(commit) => commit.clone(),
+            None => {
+                let commit = CommitInfo::from_commit(&repo.find_commit(commit_id)?);
+                commits.insert(commit_id, commit.clone());
+                commit
+            },
+        };
+        let orig_path = hunk.path().map(|orig_path| orig_path.to_string_lossy().into_owned());
+        ranges.push(BlameRange {
+            start_line: hunk.final_start_line(),
+            lines: hunk.lines_in_hunk(),
+            commit,
+            path: orig_path.filter(|orig_path| orig_path != path),
+        });
+    }
+    Ok(ranges)
+}
+
+pub async fn get_blame_path(
+    AppPath(path): AppPath<String>,
+    AppQuery(query): AppQuery<BlameQuery>,
+    extract::State(state): extract::State<AppState>,
+    extract::Extension(principal): extract::Extension<Principal>,
+) -> Result<Json<Blame>, AppError> {
+    tracing::debug!("history::get_blame_path");
+
+    if !principal.acl.can_read(&path) {
+        return Err(AppError::NotFound);
+    }
+
+    let repo = state.repo.lock().unwrap();
+    let commit = match &query.rev {
+        Some(rev) => repo.find_commit(resolve_revision(&repo, rev)?)?,
+        None => head_commit(&repo)?.ok_or(AppError::NotFound)?,
+    };
+    if blob_at(Some(&commit.tree()?), path.as_ref()).is_none() {
+        return Err(AppError::NotFound);
+    }
+    Ok(Json(Blame {
+        commit_id: commit.id().to_string(),
+        ranges: collect_blame(&repo, &path, commit.id())?,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Commit a snapshot of `files` on top of HEAD.
+    fn commit(repo: &Repository, files: &[(&str, &str)], message: &str) -> Oid {
+        let mut index = Index::new().unwrap();
+        for (path, content) in files {
+            index.add(&blob_index_entry(path, repo.blob(content.as_bytes()).unwrap())).unwrap();
+        }
+        let tree = repo.find_tree(index.write_tree_to(repo).unwrap()).unwrap();
+        let signature = git2::Signature::now("test", "test@example.com").unwrap();
+        let parent = head_commit(repo).unwrap();
+        repo.commit(Some("HEAD"), &signature, &signature, message, &tree, &parent.iter().collect::<Vec<_>>()).unwrap()
+    }
+
+    fn summary(history: &[HistoryEntry]) -> Vec<(&str, &str, Option<&str>)> {
+        history.iter()
+            .map(|entry| (entry.commit.message.as_str(), entry.path.as_str(), entry.renamed_from.as_deref()))
+            .collect()
+    }
+
+    /// A repository where `a.md` is created in `private/`, edited, and moved to `notes/`.
+    fn renamed_file() -> (tempfile::TempDir, Repository) {
+        let dir = tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        let content = "one\ntwo\nthree\nfour\nfive\n";
+        commit(&repo, &[("private/a.md", "one\ntwo\nthree\nfour\n")], "create");
+        commit(&repo, &[("private/a.md", content), ("b.md", "b\n")], "edit");
+        commit(&repo, &[("notes/a.md", content), ("b.md", "b\n")], "move");
+        commit(&repo, &[("notes/a.md", content), ("b.md", "b!\n")], "unrelated");
+        commit(&repo, &[("notes/a.md", "one\ntwo\nthree\nfour\nfive\nsix\n"), ("b.md", "b!\n")], "append");
+        (dir, repo)
+    }
+
+    #[test]
+    fn file_history_follows_renames() {
+        let (_dir, repo) = renamed_file();
+        let history = collect_file_history(&repo, &PathAccess::default(), "notes/a.md", 0, 10).unwrap();
+        assert_eq!(summary(&history), vec![
+            ("append", "notes/a.md", None),
+            ("move", "notes/a.md", Some("private/a.md")),
+            ("edit", "private/a.md", None),
+            ("create", "private/a.md", None),
+        ]);
+        assert_eq!((history[0].added, history[0].removed), (1, 0));
+        // A pure rename changes no lines
+        assert_eq!((history[1].added, history[1].removed), (0, 0));
+    }
+
+    #[test]
+    fn file_history_pages_with_offset_and_limit() {
+        let (_dir, repo) = renamed_file();
+        let history = collect_file_history(&repo, &PathAccess::default(), "notes/a.md", 1, 2).unwrap();
+        assert_eq!(summary(&history), vec![
+            ("move", "notes/a.md", Some("private/a.md")),
+            ("edit", "private/a.md", None),
+        ]);
+        assert!(collect_file_history(&repo, &PathAccess::default(), "notes/a.md", 0, 0).unwrap().is_empty());
+        assert!(collect_file_history(&repo, &PathAccess::default(), "notes/a.md", 4, 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn file_history_stops_at_unreadable_paths() {
+        let (_dir, repo) = renamed_file();
+        let mut acl = PathAccess::default();
+        acl.add_rule_set(vec![AclRule { id: 0, subject: "alice".to_string(), pattern: "notes/**".to_string(), access: Access::Read }]);
+        let history = collect_file_history(&repo, &acl, "notes/a.md", 0, 10).unwrap();
+        // The move looks like the file was added, without telling where it came from
+        assert_eq!(summary(&history), vec![
+            ("append", "notes/a.md", None),
+            ("move", "notes/a.md", None),
+        ]);
+        assert_eq!((history[1].added, history[1].removed), (5, 0));
+    }
+}
@@ -1,9 +1,11 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::env;
 use std::ffi::OsStr;
 use std::io::Write;
 use std::iter::once;
+use std::net::{IpAddr, SocketAddr};
 use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
 use std::process::Stdio;
 use std::str::FromStr;
@@ -13,11 +15,8 @@ use std::sync::{Arc, Mutex};
 use std::time;
 
 use anyhow::{Context, Result};
-use argon2;
 use axum::{
-    BoxError,
     body::Body,
-    error_handling::HandleErrorLayer,
     extract,
     http::{
         header,
@@ -27,24 +26,26 @@ use axum::{
         Request,
         StatusCode,
     },
+    Extension,
     Json,
     middleware::{self, Next},
     response::{IntoResponse, Response},
     Router,
-    routing::{get, post},
+    routing::{delete, get, post, put},
 };
-use chrono::{DateTime, Duration, Utc};
+use chrono::{DateTime, Duration, Timelike, Utc};
 use dotenv::dotenv;
 use git2::{Index, IndexEntry, IndexTime, Repository, Oid};
 use jsonwebtoken as jwt;
-use mime_guess;
-use reqwest;
 use serde::{Deserialize, Serialize};
 use sha1::{Digest, Sha1};
+use sha2::Sha256;
+use hmac::{Hmac, Mac};
 use sqlx::sqlite::{
     SqliteConnection,
     SqliteConnectOptions,
     SqliteJournalMode,
+    SqlitePool,
     SqlitePoolOptions,
 };
 use sqlx::{Connection, Row};
@@ -61,6 +62,15 @@ use tower_http::{
 };
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
+mod acl;
+mod auth;
+mod history;
+mod oidc;
+mod totp;
+mod trash;
+
+use acl::*;
+use auth::*;
 use models::*;
 
 #[tokio::main]
@@ -77,13 +87,19 @@ async fn main() -> Result<()> {
     let cache_db_opts = SqliteConnectOptions::from_str(cache_db_url)?
         .create_if_missing(true)
         .journal_mode(SqliteJournalMode::Wal);
-    let mut cache_writer_conn = SqliteConnection::connect_with(&cache_db_opts.clone().read_only(false))
+    // The writer comes first, as read-only connections cannot create the database
+    let cache_writer_pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect_with(cache_db_opts.clone().read_only(false))
         .await?;
     let cache_reader_pool = SqlitePoolOptions::new()
         .max_connections(4)
         .connect_with(cache_db_opts.read_only(true))
         .await?;
+    // The only writer, shared by handlers and the cache manager
+    let mut cache_writer_conn = cache_writer_pool.acquire().await?;
     init_cache_database(&mut cache_writer_conn).await?;
+    seed_initial_user(&mut cache_writer_conn).await?;
 
     let repo: Arc<Mutex<Repository>> = {
         let git_dir = env::var("MORIED_GIT_DIR").unwrap();
@@ -98,7 +114,11 @@ async fn main() -> Result<()> {
     let state = models::AppState {
         repo: repo.clone(),
         cache_db: cache_reader_pool,
+        cache_db_writer: cache_writer_pool,
         tx: refresh_tx,
+        login_throttle: Arc::new(Mutex::new(LoginThrottle::from_env())),
+        autosave: Arc::new(Mutex::new(AutosaveCoalescing::from_env())),
+        signer: CommitSigner::from_env().map(Arc::new),
         http_client: reqwest::Client::builder()
             .gzip(true)
             .brotli(true)
@@ -106,6 +126,17 @@ async fn main() -> Result<()> {
             .context("Failed to build a reqwest client")
             .unwrap(),
     };
+    if sync_worktree_enabled() && repo.lock().unwrap().is_bare() {
+        tracing::warn!("MORIED_SYNC_WORKTREE has no effect on a bare repository");
+    }
+
+    if let Some(signer) = &state.signer {
+        match signer.check() {
+            Ok(()) => tracing::info!("Signing commits with the {} key {}", signer.format, signer.key),
+            Err(e) => tracing::error!("Commits cannot be signed with the {} key {}: {:#}", signer.format, signer.key, e),
+        }
+    }
+
     match state.check_cache_state().await? {
         CacheState::Stale { cache_commit_id, .. } => {
             // Perform delta update
@@ -121,11 +152,12 @@ async fn main() -> Result<()> {
         },
         _ => (),
     }
+    drop(cache_writer_conn);
 
     tokio::spawn(cache_manager_task(
         repo.clone(),
         refresh_rx,
-        cache_writer_conn,
+        state.cache_db_writer.clone(),
     ));
 
     let addr = env::var("MORIED_LISTEN").unwrap();
@@ -133,7 +165,7 @@ async fn main() -> Result<()> {
 
     let cors = CorsLayer::new()
         .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
-        .allow_headers([header::AUTHORIZATION, header::CONTENT_TYPE, header::IF_NONE_MATCH])
+        .allow_headers([header::AUTHORIZATION, header::CONTENT_TYPE, header::IF_MATCH, header::IF_NONE_MATCH])
         .allow_origin(env::var("MORIED_ORIGIN_ALLOWED").unwrap().parse::<HeaderValue>().unwrap())
         .allow_credentials(true);
 
@@ -142,34 +174,65 @@ async fn main() -> Result<()> {
         .route("/notes/*path", get(get_notes_path).put(put_notes_path).delete(delete_notes_path))
         .route("/files", post(post_files).layer(extract::DefaultBodyLimit::max(16 * 1024 * 1024)))
         .route("/files/*path", get(get_files_path))
+        .route("/logout", post(post_logout))
+        .route("/logout/all", post(post_logout_all))
         .with_state(state.clone())
-        .route_layer(middleware::from_fn(auth));
+        .route_layer(middleware::from_fn_with_state(state.clone(), auth));
     let login_api = Router::new()
         .route("/login", post(post_login))
-        .route_layer(
-            ServiceBuilder::new()
-                .layer(HandleErrorLayer::new(|_: BoxError| async {
-                    // Too many requests
-                    StatusCode::SERVICE_UNAVAILABLE
-                }))
-                .load_shed()
-                .buffer(1)  // Required to make it Clone.
-                .rate_limit(1, time::Duration::from_secs(3))
-        );
+        .with_state(state.clone());
+    let public_api = Router::new()
+        .route("/refresh", post(post_refresh))
+        .route("/oidc/login", get(oidc::get_oidc_login))
+        .route("/oidc/callback", post(oidc::post_oidc_callback))
+        .route("/share/:id", get(get_share_id))
+        .with_state(state.clone());
     let protected_api_v2 = Router::new()
+        .route("/commits", post(v2::post_commits).layer(extract::DefaultBodyLimit::max(16 * 1024 * 1024)))
         .route("/commits/head", get(v2::get_commits_head))
         .route("/files/*path", get(v2::get_files_path).head(v2::head_files_path))
+        .route("/history/*path", get(history::get_history_path))
+        .route("/blame/*path", get(history::get_blame_path))
+        .route("/diff", get(history::get_diff))
+        .route("/diff/*path", get(history::get_diff_path))
         .route("/tasks", get(v2::get_tasks))
         .route("/events", get(v2::get_events))
         .route("/assess-task", post(v2::post_assess_task))
+        .route("/tokens", get(auth::get_tokens).post(auth::post_tokens))
+        .route("/tokens/:id", delete(auth::delete_tokens_id))
+        .route("/shares", get(v2::get_shares).post(v2::post_shares))
+        .route("/shares/:id", delete(v2::delete_shares_id))
+        .route("/trash", get(trash::get_trash))
+        .route("/trash/restore", post(trash::post_trash_restore))
+        .route("/restore/*path", post(v2::post_restore_path))
+        .route("/revert", post(v2::post_revert))
+        .route("/account/totp", post(auth::post_account_totp))
+        .route("/account/totp/confirm", post(auth::post_account_totp_confirm))
+        .route("/account/totp/disable", post(auth::post_account_totp_disable))
+        .route("/account/totp/recovery-codes", post(auth::post_account_totp_recovery_codes))
+        .with_state(state.clone())
+        .route_layer(middleware::from_fn_with_state(state.clone(), auth));
+    let admin_api_v2 = Router::new()
+        .route("/admin/users", get(v2::get_admin_users).post(v2::post_admin_users))
+        .route("/admin/users/:name/disable", post(v2::post_admin_users_disable))
+        .route("/admin/users/:name/enable", post(v2::post_admin_users_enable))
+        .route("/admin/users/:name/password", put(v2::put_admin_users_password))
+        .route("/admin/users/:name/totp/disable", post(auth::post_admin_users_totp_disable))
+        .route("/admin/acl", get(acl::get_admin_acl).post(acl::post_admin_acl))
+        .route("/admin/acl/:id", delete(acl::delete_admin_acl_id))
+        .route("/admin/audit", get(v2::get_admin_audit))
         .with_state(state.clone())
-        .route_layer(middleware::from_fn(auth));
+        .route_layer(middleware::from_fn_with_state(state.clone(), require_admin))
+        .route_layer(middleware::from_fn_with_state(state.clone(), auth));
     let api_v2 = Router::new()
-        .merge(protected_api_v2);
+        .merge(protected_api_v2)
+        .merge(admin_api_v2);
     let api = Router::new()
         .merge(protected_api)
         .merge(login_api)
+        .merge(public_api)
         .nest("/v2", api_v2)
+        .layer(middleware::from_fn_with_state(state.clone(), audit))
         .layer(
             ServiceBuilder::new()
                 .layer(TraceLayer::new_for_http())
@@ -191,7 +254,7 @@ async fn main() -> Result<()> {
     };
 
     let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, app.into_make_service())
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
         .await
         .unwrap();
 
@@ -234,16 +297,217 @@ async fn init_cache_database(
         ")
         .execute(&mut *conn)
         .await?;
+    sqlx::query("
+            CREATE TABLE IF NOT EXISTS user (
+                name          TEXT PRIMARY KEY,
+                email         TEXT NOT NULL,
+                display_name  TEXT,
+                hash          TEXT NOT NULL,
+                admin         INTEGER NOT NULL DEFAULT 0,
+                disabled      INTEGER NOT NULL DEFAULT 0,
+                created_at    INTEGER NOT NULL
+            ) STRICT;
+        ")
+        .execute(&mut *conn)
+        .await?;
+    add_column_if_missing(conn, "user", "oidc_issuer", "TEXT").await?;
+    add_column_if_missing(conn, "user", "oidc_subject", "TEXT").await?;
+    sqlx::query("CREATE UNIQUE INDEX IF NOT EXISTS user_oidc_identity ON user (oidc_issuer, oidc_subject);")
+        .execute(&mut *conn)
+        .await?;
+    add_column_if_missing(conn, "user", "totp_secret", "TEXT").await?;
+    add_column_if_missing(conn, "user", "totp_enabled", "INTEGER NOT NULL DEFAULT 0").await?;
+    add_column_if_missing(conn, "user", "totp_last_step", "INTEGER").await?;
+    sqlx::query("
+            CREATE TABLE IF NOT EXISTS recovery_code (
+                user       TEXT NOT NULL,
+                code_hash  TEXT NOT NULL,
+                used_at    INTEGER,
+                PRIMARY KEY (user, code_hash)
+            ) STRICT;
+        ")
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query("
+            CREATE TABLE IF NOT EXISTS session (
+                id                  TEXT PRIMARY KEY,
+                user                TEXT NOT NULL,
+                refresh_token_hash  TEXT NOT NULL UNIQUE,
+                created_at          INTEGER NOT NULL,
+                expires_at          INTEGER NOT NULL,
+                revoked_at          INTEGER
+            ) STRICT;
+        ")
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query("
+            CREATE TABLE IF NOT EXISTS api_token (
+                id            TEXT PRIMARY KEY,
+                user          TEXT NOT NULL,
+                name          TEXT NOT NULL,
+                token_hash    TEXT NOT NULL UNIQUE,
+                scopes        TEXT NOT NULL,
+                path_prefix   TEXT,
+                created_at    INTEGER NOT NULL,
+                last_used_at  INTEGER,
+                revoked_at    INTEGER
+            ) STRICT;
+        ")
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query("
+            CREATE TABLE IF NOT EXISTS acl_rule (
+                id       INTEGER PRIMARY KEY,
+                subject  TEXT NOT NULL,
+                pattern  TEXT NOT NULL,
+                access   TEXT NOT NULL
+            ) STRICT;
+        ")
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query("
+            CREATE TABLE IF NOT EXISTS share_link (
+                id          TEXT PRIMARY KEY,
+                path        TEXT NOT NULL,
+                blob_id     TEXT,
+                created_by  TEXT NOT NULL,
+                created_at  INTEGER NOT NULL,
+                expires_at  INTEGER NOT NULL,
+                revoked_at  INTEGER
+            ) STRICT;
+        ")
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query("
+            CREATE TABLE IF NOT EXISTS audit_log (
+                id          INTEGER PRIMARY KEY,
+                time        INTEGER NOT NULL,
+                actor       TEXT,
+                token_id    TEXT,
+                method      TEXT NOT NULL,
+                route       TEXT NOT NULL,
+                path        TEXT,
+                status      INTEGER NOT NULL,
+                commit_id   TEXT,
+                client_ip   TEXT NOT NULL
+            ) STRICT;
+        ")
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query("CREATE INDEX IF NOT EXISTS audit_log_time ON audit_log (time);")
+        .execute(&mut *conn)
+        .await?;
+    // The audit log is append-only
+    sqlx::query("
+            CREATE TRIGGER IF NOT EXISTS audit_log_no_update BEFORE UPDATE ON audit_log
+            BEGIN SELECT RAISE(ABORT, 'audit_log is append-only'); END;
+        ")
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query("
+            CREATE TRIGGER IF NOT EXISTS audit_log_no_delete BEFORE DELETE ON audit_log
+            BEGIN SELECT RAISE(ABORT, 'audit_log is append-only'); END;
+        ")
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query("
+            CREATE TABLE IF NOT EXISTS trash (
+                id          INTEGER PRIMARY KEY,
+                path        TEXT NOT NULL,
+                blob_id     TEXT NOT NULL,
+                mode        INTEGER NOT NULL,
+                commit_id   TEXT NOT NULL,
+                deleted_by  TEXT NOT NULL,
+                deleted_at  INTEGER NOT NULL
+            ) STRICT;
+        ")
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query("CREATE INDEX IF NOT EXISTS trash_path ON trash (path);")
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query("
+            CREATE TABLE IF NOT EXISTS oidc_login (
+                state          TEXT PRIMARY KEY,
+                nonce          TEXT NOT NULL,
+                code_verifier  TEXT NOT NULL,
+                created_at     INTEGER NOT NULL
+            ) STRICT;
+        ")
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+/// Add a column to a table created by an older version.
+async fn add_column_if_missing(
+    conn: &mut SqliteConnection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<()> {
+    let exists: bool = sqlx::query_scalar("SELECT COUNT(*) > 0 FROM pragma_table_info(?) WHERE name = ?;")
+        .bind(table)
+        .bind(column)
+        .fetch_one(&mut *conn)
+        .await?;
+    if !exists {
+        sqlx::query(&format!("ALTER TABLE {} ADD COLUMN {} {};", table, column, definition))
+            .execute(&mut *conn)
+            .await
+            .with_context(|| format!("Failed to add column {}.{}", table, column))?;
+        tracing::info!("Added column {}.{}", table, column);
+    }
+    Ok(())
+}
+
+/// Create the first administrator account from `MORIED_USER_*` if no account exists yet.
+async fn seed_initial_user(
+    conn: &mut SqliteConnection,
+) -> Result<()> {
+    let user_count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM user;")
+        .fetch_one(&mut *conn)
+        .await?;
+    if user_count > 0 {
+        return Ok(());
+    }
+
+    match (env::var("MORIED_USER_NAME"), env::var("MORIED_USER_EMAIL"), env::var("MORIED_USER_HASH")) {
+        (Ok(name), Ok(email), Ok(hash)) => {
+            sqlx::query("INSERT INTO user (name, email, hash, admin, created_at) VALUES (?, ?, ?, 1, ?);")
+                .bind(&name)
+                .bind(email)
+                .bind(hash)
+                .bind(Utc::now().timestamp())
+                .execute(&mut *conn)
+                .await
+                .context("Failed to create the initial user")?;
+            tracing::info!("Created initial administrator account '{}' from MORIED_USER_NAME", name);
+        },
+        _ => {
+            tracing::warn!("No user account exists; set MORIED_USER_NAME, MORIED_USER_EMAIL and MORIED_USER_HASH to create one");
+        },
+    }
     Ok(())
 }
 
 async fn cache_manager_task(
     repo: Arc<Mutex<Repository>>,
     mut rx: watch::Receiver<CacheState>,
-    mut conn: SqliteConnection,
+    writer: SqlitePool,
 ) {
     while rx.changed().await.is_ok() {
         let cache_state = rx.borrow_and_update().clone();
+        if !matches!(cache_state, CacheState::Stale { .. } | CacheState::Diverged { .. } | CacheState::Empty(_)) {
+            continue;
+        }
+        let mut conn = match writer.acquire().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                tracing::error!("failed to acquire the cache database writer: {:?}", e);
+                continue;
+            },
+        };
         match cache_state {
             CacheState::Stale { cache_commit_id, .. } => {
                 // Perform delta update
@@ -268,66 +532,587 @@ async fn cache_manager_task(
     }
 }
 
-async fn auth(req: Request<Body>, next: Next) -> Result<Response, StatusCode> {
-    let auth_header = req
-        .headers()
-        .get(header::AUTHORIZATION)
-        .and_then(|value| value.to_str().ok());
+/// Record a request in the audit log.
+///
+/// Every call except reads is recorded, as well as file downloads and any
+/// use of a personal access token. Handlers report who made the call and
+/// which commit they created through `AuditActor` and `CommitCreated`
+/// response extensions.
+async fn audit(
+    extract::State(state): extract::State<AppState>,
+    extract::ConnectInfo(addr): extract::ConnectInfo<SocketAddr>,
+    matched_path: Option<extract::MatchedPath>,
+    path_params: Option<extract::RawPathParams>,
+    req: Request<Body>,
+    next: Next,
+) -> Response {
+    let method = req.method().clone();
+    let route = matched_path.as_ref().map_or(String::new(), |p| relative_route(p.as_str()));
+    let path = path_params.as_ref()
+        .and_then(|params| params.iter().find(|(key, _)| *key == "path").map(|(_, value)| value.to_owned()));
+    let ip = client_ip(addr, req.headers());
+
+    let res = next.run(req).await;
+
+    let actor = res.extensions().get::<AuditActor>();
+    let is_download = matches!(route.as_str(), "/files/*path" | "/v2/files/*path" | "/share/:id");
+    let is_read = method == Method::GET || method == Method::HEAD || method == Method::OPTIONS;
+    let is_token_use = actor.is_some_and(|actor| actor.token_id.is_some());
+    if is_read && !(method == Method::GET && is_download) && !is_token_use {
+        return res;
+    }
 
-    match auth_header {
-        Some(auth_header) if token_is_valid(auth_header) => {
-            Ok(next.run(req).await)
-        },
-        _ => Err(StatusCode::UNAUTHORIZED),
+    let result = sqlx::query("
+            INSERT INTO audit_log (time, actor, token_id, method, route, path, status, commit_id, client_ip)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?);
+        ")
+        .bind(Utc::now().timestamp())
+        .bind(actor.map(|actor| &actor.user))
+        .bind(actor.and_then(|actor| actor.token_id.as_ref()))
+        .bind(method.as_str())
+        .bind(&route)
+        .bind(&path)
+        .bind(res.status().as_u16())
+        .bind(res.extensions().get::<CommitCreated>().map(|commit| commit.0.to_string()))
+        .bind(ip.to_string())
+        .execute(&state.cache_db_writer)
+        .await;
+    if let Err(e) = result {
+        tracing::error!("failed to write the audit log for {} {}: {:?}", method, route, e);
     }
+    res
 }
 
-fn token_is_valid(header_value: &str) -> bool {
-    let token = header_value.split_whitespace().nth(1).unwrap();
+/// Strip `MORIED_ROOT_PATH` from a matched route.
+fn relative_route(matched_path: &str) -> String {
+    let root_path = env::var("MORIED_ROOT_PATH").unwrap_or_else(|_| "/".to_owned());
+    let root_path = root_path.trim_end_matches('/');
+    matched_path.strip_prefix(root_path).unwrap_or(matched_path).to_owned()
+}
 
-    let secret = env::var("MORIED_SECRET").unwrap();
-    match jwt::decode::<Claims>(&token, &jwt::DecodingKey::from_secret(secret.as_ref()), &jwt::Validation::default()) {
-        Ok(_) => {
-            tracing::debug!("authorized");
-            true
+/// Create a commit on top of HEAD.
+///
+/// The commit is authored by the requesting user while the repository's own identity is used as the committer.
+fn commit_to_head(
+    repo: &Repository,
+    signer: Option<&CommitSigner>,
+    author: &git2::Signature,
+    message: &str,
+    tree: &git2::Tree,
+    parents: &[&git2::Commit],
+) -> Result<Oid, AppError> {
+    let worktree_paths = check_worktree(repo, tree)?;
+    let committer = repo.signature()?;
+    let commit_oid = match signer {
+        Some(signer) => {
+            let commit_oid = create_signed_commit(repo, signer, author, &committer, message, tree, parents)?;
+            set_head(repo, commit_oid, parents.first().map(|parent| parent.id()), message)?;
+            commit_oid
         },
+        None => repo.commit(Some("HEAD"), author, &committer, message, tree, parents)?,
+    };
+    sync_worktree(repo, tree, &worktree_paths);
+    Ok(commit_oid)
+}
+
+/// Whether the server created a commit, which it commits as the repository's own identity.
+///
+/// Unlike the audit log, the committer stays the same when a save amends the commit.
+fn is_server_commit(repo: &Repository, commit: &git2::Commit) -> Result<bool, git2::Error> {
+    let server = repo.signature()?;
+    let committer = commit.committer();
+    Ok(committer.name_bytes() == server.name_bytes() && committer.email_bytes() == server.email_bytes())
+}
+
+fn sync_worktree_enabled() -> bool {
+    env::var("MORIED_SYNC_WORKTREE").is_ok_and(|v| v == "true")
+}
+
+/// Paths of the working tree a commit of `tree` on top of HEAD would change, if it is to be kept in sync.
+///
+/// Fails with a conflict if any of them has local changes, which the sync would overwrite.
+fn check_worktree(repo: &Repository, tree: &git2::Tree) -> Result<Vec<String>, AppError> {
+    if !sync_worktree_enabled() || repo.is_bare() {
+        return Ok(Vec::new());
+    }
+
+    let head_tree = match head_commit(repo)? {
+        Some(head_commit) => Some(head_commit.tree()?),
+        None => None,
+    };
+    let diff = repo.diff_tree_to_tree(head_tree.as_ref(), Some(tree), None)?;
+    let mut paths: Vec<String> = diff.deltas()
+        .flat_map(|delta| [delta.old_file().path(), delta.new_file().path()])
+        .flatten()
+        .filter_map(|path| path.to_str().map(str::to_owned))
+        .collect();
+    paths.sort();
+    paths.dedup();
+    if paths.is_empty() {
+        return Ok(paths);
+    }
+
+    let mut options = git2::StatusOptions::new();
+    options.include_untracked(true).recurse_untracked_dirs(true).disable_pathspec_match(true);
+    for path in &paths {
+        options.pathspec(path);
+    }
+    let changed: Vec<String> = repo.statuses(Some(&mut options))?
+        .iter()
+        .filter(|entry| !entry.status().is_ignored())
+        .filter_map(|entry| entry.path().map(str::to_owned))
+        .collect();
+    if !changed.is_empty() {
+        tracing::warn!("Refused to commit over local changes in the working tree: {}", changed.join(", "));
+        return Err(AppError::conflict("the working tree has local changes", ConflictingPaths { paths: changed }));
+    }
+    Ok(paths)
+}
+
+/// Bring `paths` of the working tree and the index up to date with the committed `tree`.
+///
+/// The commit has been made already, so failures are only reported.
+fn sync_worktree(repo: &Repository, tree: &git2::Tree, paths: &[String]) {
+    let Some(workdir) = repo.workdir() else {
+        return;
+    };
+    if paths.is_empty() {
+        return;
+    }
+    match update_worktree(repo, workdir, tree, paths) {
+        Ok(()) => tracing::debug!("Synced {} paths of the working tree", paths.len()),
+        Err(e) => tracing::error!("Failed to sync the working tree with the commit: {:#}", e),
+    }
+}
+
+fn update_worktree(repo: &Repository, workdir: &Path, tree: &git2::Tree, paths: &[String]) -> Result<()> {
+    let mut index = repo.index()?;
+    for path in paths {
+        let file_path = workdir.join(path);
+        match tree.get_path(Path::new(path)) {
+            Ok(entry) => {
+                let blob = repo.find_blob(entry.id())?;
+                if let Some(parent) = file_path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                if entry.filemode() == i32::from(git2::FileMode::Link) {
+                    let _ = std::fs::remove_file(&file_path);
+                    std::os::unix::fs::symlink(OsStr::from_bytes(blob.content()), &file_path)?;
+                }
+                else {
+                    std::fs::write(&file_path, blob.content())?;
+                    let mode = if entry.filemode() == i32::from(git2::FileMode::BlobExecutable) { 0o755 } else { 0o644 };
+                    std::fs::set_permissions(&file_path, std::fs::Permissions::from_mode(mode))?;
+                }
+                index.add_path(Path::new(path))?;
+            },
+            Err(_) => {
+                match std::fs::remove_file(&file_path) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                    _ => (),
+                }
+                // Remove directories left empty, like git does
+                for dir in file_path.ancestors().skip(1).take_while(|dir| *dir != workdir) {
+                    if std::fs::remove_dir(dir).is_err() {
+                        break;
+                    }
+                }
+                index.remove_path(Path::new(path))?;
+            },
+        }
+    }
+    index.write()?;
+    Ok(())
+}
+
+/// Create a commit signed with the configured key, without updating any reference.
+fn create_signed_commit(
+    repo: &Repository,
+    signer: &CommitSigner,
+    author: &git2::Signature,
+    committer: &git2::Signature,
+    message: &str,
+    tree: &git2::Tree,
+    parents: &[&git2::Commit],
+) -> Result<Oid> {
+    let buffer = repo.commit_create_buffer(author, committer, message, tree, parents)?;
+    let content = buffer.as_str().context("Commit content should be UTF-8")?;
+    // The repository stays locked meanwhile, but other requests move off this worker thread
+    let signature = match tokio::task::block_in_place(|| signer.sign(&buffer)) {
+        Ok(signature) => signature,
         Err(e) => {
-            tracing::debug!("failed to decode token: {:?}", e);
-            false
+            // Refuse to commit unsigned when signing is required
+            tracing::error!("Failed to sign a commit with the {} key {}: {:#}", signer.format, signer.key, e);
+            return Err(e);
+        },
+    };
+    Ok(repo.commit_signed(content, &signature, None)?)
+}
+
+/// Move HEAD, or the branch it points to, from `expected` to a new commit.
+///
+/// Fails with a conflict if HEAD has moved from `expected`, or already exists when `None` is expected.
+fn set_head(repo: &Repository, commit_oid: Oid, expected: Option<Oid>, message: &str) -> Result<(), AppError> {
+    let log_message = format!("commit: {}", message.lines().next().unwrap_or(""));
+    let head = repo.find_reference("HEAD")?;
+    let result = match (head.symbolic_target(), expected) {
+        (Some(branch), Some(expected)) => repo.reference_matching(branch, commit_oid, true, expected, &log_message).map(|_| ()),
+        (Some(branch), None) => repo.reference(branch, commit_oid, false, &log_message).map(|_| ()),
+        (None, expected) if head.target() == expected => repo.set_head_detached(commit_oid),
+        (None, _) => return Err(AppError::conflict("HEAD has moved", ())),
+    };
+    match result {
+        Err(e) if matches!(e.code(), git2::ErrorCode::Modified | git2::ErrorCode::Exists) => {
+            tracing::warn!("HEAD has moved while committing {}: {}", commit_oid, e.message());
+            Err(AppError::conflict("HEAD has moved", ()))
         },
+        result => Ok(result?),
     }
 }
 
-async fn post_login(
-    Json(login): Json<Login>,
-) -> Response {
-    tracing::debug!("post_login");
-    let user_name = env::var("MORIED_USER_NAME").unwrap();
-    let user_email = env::var("MORIED_USER_EMAIL").unwrap();
-    let user_hash = env::var("MORIED_USER_HASH").unwrap();
-    let matches = user_name == login.user && argon2::verify_encoded(&user_hash, login.password.as_ref()).unwrap();
-
-    if matches {
-        let secret = env::var("MORIED_SECRET").unwrap();
-        let duration = env::var("MORIED_SESSION_EXPIRY_MINUTES").map_or(Duration::hours(6), |v| {
-            Duration::minutes(v.parse::<i64>().expect("Session duration in minutes represented as integer value is expected"))
+/// HEAD commit, or `None` in a repository without commits yet.
+fn head_commit(repo: &Repository) -> Result<Option<git2::Commit<'_>>, git2::Error> {
+    match repo.head() {
+        Ok(head) => Ok(Some(head.peel_to_commit()?)),
+        Err(e) if e.code() == git2::ErrorCode::UnbornBranch || e.code() == git2::ErrorCode::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// In-memory index of the tree of `commit`, or an empty one without a commit.
+fn index_of(commit: Option<&git2::Commit>) -> Result<Index, git2::Error> {
+    let mut index = Index::new()?;
+    if let Some(commit) = commit {
+        index.read_tree(&commit.tree()?)?;
+    }
+    Ok(index)
+}
+
+/// Resolve a commit ID, a tag, a branch or `@{<date>}`, the last commit on HEAD at that date, to a commit.
+fn resolve_revision(repo: &Repository, rev: &str) -> Result<Oid, AppError> {
+    let Some(date) = rev.strip_prefix("@{").and_then(|rest| rest.strip_suffix('}')) else {
+        let object = repo.revparse_single(rev).map_err(|_| AppError::NotFound)?;
+        return Ok(object.peel_to_commit().map_err(|_| AppError::NotFound)?.id());
+    };
+
+    let time = parse_revision_date(date)?;
+    let head_commit = head_commit(repo)?.ok_or(AppError::NotFound)?;
+    for commit in history::walk_commits(repo, head_commit.id(), None, true)? {
+        let commit = commit?;
+        if commit.committer().when().seconds() <= time {
+            return Ok(commit.id());
+        }
+    }
+    Err(AppError::NotFound)
+}
+
+/// Unix time of an RFC 3339 date and time, or of the start of a `YYYY-MM-DD` date in UTC.
+fn parse_revision_date(date: &str) -> Result<i64, AppError> {
+    match DateTime::parse_from_rfc3339(date) {
+        Ok(time) => Ok(time.timestamp()),
+        Err(_) => Ok(chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d")
+            .map_err(|_| AppError::BadRequest(format!("invalid date: {}", date)))?
+            .and_time(chrono::NaiveTime::MIN)
+            .and_utc()
+            .timestamp()),
+    }
+}
+
+/// Check the `If-Match` precondition of a write to `path`.
+fn check_if_match(repo: &Repository, headers: &HeaderMap, path: &str) -> Result<(), AppError> {
+    let Some(if_match) = headers.get(header::IF_MATCH) else {
+        return Ok(());
+    };
+    check_version(repo, if_match.to_str().unwrap_or(""), path)
+}
+
+/// Check that `path` is at a version the client expects, given as in `If-Match`.
+///
+/// The versions may name the blob of the path or a commit, and match if the
+/// path is unchanged at HEAD since that version. On a mismatch the conflict
+/// carries the current version.
+fn check_version(repo: &Repository, if_match: &str, path: &str) -> Result<(), AppError> {
+    let head_commit = head_commit(repo)?;
+    let blob_at = |commit: &git2::Commit| -> Result<Option<Oid>, git2::Error> {
+        Ok(commit.tree()?.get_path(Path::new(path)).ok().map(|entry| entry.id()))
+    };
+    let current = match &head_commit {
+        Some(commit) => blob_at(commit)?,
+        None => None,
+    };
+
+    for tag in if_match.split(',').map(|tag| tag.trim().trim_start_matches("W/").trim_matches('"')) {
+        if tag == "*" {
+            if current.is_some() {
+                return Ok(());
+            }
+            continue;
+        }
+        let Ok(oid) = Oid::from_str(tag) else {
+            continue;
+        };
+        if current == Some(oid) {
+            return Ok(());
+        }
+        if let Ok(commit) = repo.find_commit(oid) {
+            if blob_at(&commit)? == current {
+                return Ok(());
+            }
+        }
+    }
+
+    tracing::debug!("If-Match {} does not match {}", if_match, path);
+    let version = NoteVersion {
+        commit_id: head_commit.as_ref().map(|commit| commit.id().to_string()),
+        blob_id: current.map(|oid| oid.to_string()),
+        content: current
+            .and_then(|oid| repo.find_blob(oid).ok())
+            .and_then(|blob| String::from_utf8(blob.content().to_vec()).ok()),
+    };
+    Err(AppError::conflict(format!("{} has changed", path), ConflictingVersion { current: version }))
+}
+
+/// Index entry of a regular file at `path`.
+fn blob_index_entry(path: &str, id: Oid) -> IndexEntry {
+    IndexEntry {
+        ctime: IndexTime::new(0, 0),
+        mtime: IndexTime::new(0, 0),
+        dev: 0,
+        ino: 0,
+        mode: 0o100644,
+        uid: 0,
+        gid: 0,
+        file_size: 0,
+        id,
+        flags: 0,
+        flags_extended: 0,
+        path: path.as_bytes().into(),
+    }
+}
+
+/// Paths in `index` that are `path` itself or under the directory `path`.
+fn index_paths_under(index: &Index, path: &str) -> Vec<String> {
+    let path = path.trim_end_matches('/');
+    if path.is_empty() {
+        return Vec::new();
+    }
+    index.iter()
+        .filter_map(|entry| String::from_utf8(entry.path).ok())
+        .filter(|entry_path| entry_path == path || entry_path.strip_prefix(path).is_some_and(|rest| rest.starts_with('/')))
+        .collect()
+}
+
+/// Remove a file, or a directory with everything under it, and return the removed paths with their blobs.
+fn remove_from_index(index: &mut Index, path: &str) -> Result<Vec<trash::DeletedPath>, git2::Error> {
+    let mut removed = Vec::new();
+    for removed_path in index_paths_under(index, path) {
+        let Some(entry) = index.get_path(Path::new(&removed_path), 0) else {
+            continue;
+        };
+        index.remove_path(Path::new(&removed_path))?;
+        removed.push(trash::DeletedPath { path: removed_path, blob_id: entry.id, mode: entry.mode });
+    }
+    Ok(removed)
+}
+
+/// Rename a file, or a directory with everything under it, and return the renamed paths.
+fn rename_in_index(index: &mut Index, from: &str, to: &str) -> Result<Vec<RenamedPath>, git2::Error> {
+    let from = from.trim_end_matches('/');
+    let to = to.trim_end_matches('/');
+    let mut renamed = Vec::new();
+    for from_path in index_paths_under(index, from) {
+        let to_path = format!("{}{}", to, &from_path[from.len()..]);
+        let Some(mut entry) = index.get_path(Path::new(&from_path), 0) else {
+            continue;
+        };
+        index.remove_path(Path::new(&from_path))?;
+        entry.path = to_path.as_bytes().into();
+        index.add(&entry)?;
+        renamed.push(RenamedPath { from: from_path, to: to_path });
+    }
+    Ok(renamed)
+}
+
+/// Blob of a version of `path` in the history of `head_commit`, to merge a save from.
+///
+/// Conflicts return the text of the base, so other blobs must not be accepted.
+fn find_note_base<'r>(
+    repo: &'r Repository,
+    head_commit: Option<&git2::Commit>,
+    path: &str,
+    base: Oid,
+) -> Result<git2::Blob<'r>, AppError> {
+    if let Some(head_commit) = head_commit {
+        for commit in history::walk_commits(repo, head_commit.id(), None, false)? {
+            if commit?.tree()?.get_path(Path::new(path)).is_ok_and(|entry| entry.id() == base) {
+                return Ok(repo.find_blob(base)?);
+            }
+        }
+    }
+    Err(AppError::BadRequest(format!("base {} is not a version of {}", base, path)))
+}
+
+/// Three-way merge of a saved note with the current version, both changed from `base`.
+///
+/// Returns the merged text, or the hunks of the merge if some of them conflict.
+fn merge_note(base: &str, saved: &str, current: &str) -> Result<String, Vec<MergeHunk>> {
+    // Every version gets a newline after its last line for the merge, so that only changes to the line
+    // itself conflict, and the merged text ends with one only if the merged versions do
+    let is_terminated = |text: &str| text.is_empty() || text.ends_with('\n');
+    let terminate = |text: &str| if is_terminated(text) { text.to_owned() } else { format!("{}\n", text) };
+    let unterminate = |text: &mut String, terminated: bool| if !terminated && text.ends_with('\n') {
+        text.pop();
+    };
+    let (base_terminated, saved_terminated, current_terminated) = (is_terminated(base), is_terminated(saved), is_terminated(current));
+    let merged_terminated = if saved_terminated == base_terminated { current_terminated } else { saved_terminated };
+
+    let (base, saved, current) = (terminate(base), terminate(saved), terminate(current));
+    let base_lines: Vec<&str> = base.split_inclusive('\n').collect();
+    let versions: [Vec<&str>; 2] = [saved.split_inclusive('\n').collect(), current.split_inclusive('\n').collect()];
+    let changes = versions.each_ref().map(|lines| changed_lines(&base_lines, lines));
+
+    let mut hunks = Vec::new();
+    let push_resolved = |hunks: &mut Vec<MergeHunk>, lines: &[&str]| match hunks.last_mut() {
+        Some(MergeHunk::Resolved { text }) => text.push_str(&lines.concat()),
+        _ if lines.is_empty() => (),
+        _ => hunks.push(MergeHunk::Resolved { text: lines.concat() }),
+    };
+    // Position in the base and the matching positions in the versions, and the next change of each version
+    let mut position = 0;
+    let mut version_positions = [0, 0];
+    let mut next = [0, 0];
+    // Changes of either version to the same lines, starting from the first one, form a hunk
+    while let Some((first, (mut start, mut end))) = (0..2)
+        .filter_map(|i| changes[i].get(next[i]).map(|(old, _)| (i, (old.start, old.end))))
+        .min_by_key(|(_, (start, _))| *start)
+    {
+        let mut hunk_end = next;
+        hunk_end[first] += 1;
+        loop {
+            let mut grown = false;
+            for i in 0..2 {
+                while let Some((old, _)) = changes[i].get(hunk_end[i]) {
+                    if !(old.start < end && start < old.end || old.start == start) {
+                        break;
+                    }
+                    (start, end) = (start.min(old.start), end.max(old.end));
+                    hunk_end[i] += 1;
+                    grown = true;
+                }
+            }
+            if !grown {
+                break;
+            }
+        }
+
+        push_resolved(&mut hunks, &base_lines[position..start]);
+        // Lines before the hunk are unchanged in both versions
+        for version_position in &mut version_positions {
+            *version_position += start - position;
+        }
+        let changed = [0, 1].map(|i| hunk_end[i] > next[i]);
+        let texts = [0, 1].map(|i| {
+            let version_end = if changed[i] {
+                let (old, new) = &changes[i][hunk_end[i] - 1];
+                new.end + (end - old.end)
+            }
+            else {
+                version_positions[i] + (end - start)
+            };
+            let lines = &versions[i][version_positions[i]..version_end];
+            version_positions[i] = version_end;
+            lines
         });
-        let now: DateTime<Utc> = Utc::now();
-        let my_claims = Claims {
-            sub: login.user.to_owned(),
-            exp: (now + duration).timestamp() as usize,
-            email: user_email,
+        match changed {
+            [true, true] if texts[0] != texts[1] => hunks.push(MergeHunk::Conflict {
+                saved: texts[0].concat(),
+                base: base_lines[start..end].concat(),
+                current: texts[1].concat(),
+            }),
+            [true, _] => push_resolved(&mut hunks, texts[0]),
+            _ => push_resolved(&mut hunks, texts[1]),
+        }
+        position = end;
+        next = hunk_end;
+    }
+    push_resolved(&mut hunks, &base_lines[position..]);
+
+    if !hunks.iter().any(|hunk| matches!(hunk, MergeHunk::Conflict { .. })) {
+        let mut merged = match hunks.pop() {
+            Some(MergeHunk::Resolved { text }) => text,
+            _ => String::new(),
         };
-        let token = jwt::encode(
-            &jwt::Header::default(),
-            &my_claims,
-            &jwt::EncodingKey::from_secret(secret.as_ref())
-        ).unwrap();
-        token.into_response()
+        unterminate(&mut merged, merged_terminated);
+        return Ok(merged);
     }
-    else {
-        StatusCode::UNAUTHORIZED.into_response()
+    match hunks.last_mut() {
+        Some(MergeHunk::Resolved { text }) => unterminate(text, merged_terminated),
+        Some(MergeHunk::Conflict { saved, base, current }) => {
+            unterminate(saved, saved_terminated);
+            unterminate(base, base_terminated);
+            unterminate(current, current_terminated);
+        },
+        None => (),
+    }
+    Err(hunks)
+}
+
+/// Lines of `base` a version changed, each as the range of base lines and the range of version lines replacing them.
+fn changed_lines(base: &[&str], version: &[&str]) -> Vec<(std::ops::Range<usize>, std::ops::Range<usize>)> {
+    let mut changes: Vec<(std::ops::Range<usize>, std::ops::Range<usize>)> = Vec::new();
+    for op in similar::capture_diff_slices(similar::Algorithm::Myers, base, version) {
+        let (tag, old, new) = op.as_tag_tuple();
+        if tag == similar::DiffTag::Equal {
+            continue;
+        }
+        match changes.last_mut() {
+            // Deletions and insertions next to each other are one change
+            Some((last_old, last_new)) if last_old.end == old.start && last_new.end == new.start => {
+                last_old.end = old.end;
+                last_new.end = new.end;
+            },
+            _ => changes.push((old, new)),
+        }
+    }
+    changes
+}
+
+/// Replace HEAD with a commit of `tree` on the same parents.
+fn amend_head(
+    repo: &Repository,
+    signer: Option<&CommitSigner>,
+    author: &git2::Signature,
+    message: &str,
+    tree: &git2::Tree,
+    head_commit: &git2::Commit,
+) -> Result<Oid, AppError> {
+    let worktree_paths = check_worktree(repo, tree)?;
+    let committer = repo.signature()?;
+    let commit_oid = match signer {
+        Some(signer) => {
+            let parents: Vec<_> = head_commit.parents().collect();
+            let parents: Vec<_> = parents.iter().collect();
+            let commit_oid = create_signed_commit(repo, signer, author, &committer, message, tree, &parents)?;
+            set_head(repo, commit_oid, Some(head_commit.id()), message)?;
+            commit_oid
+        },
+        None => head_commit.amend(Some("HEAD"), Some(author), Some(&committer), None, Some(message), Some(tree))?,
+    };
+    sync_worktree(repo, tree, &worktree_paths);
+    Ok(commit_oid)
+}
+
+/// Whether a remote-tracking branch contains the commit.
+fn is_pushed(repo: &Repository, commit_id: Oid) -> Result<bool, git2::Error> {
+    for reference in repo.references_glob("refs/remotes/*")? {
+        let Some(target) = reference?.target() else {
+            continue;
+        };
+        if target == commit_id || repo.graph_descendant_of(target, commit_id)? {
+            return Ok(true);
+        }
     }
+    Ok(false)
 }
 
 enum FileOp {
@@ -337,24 +1122,21 @@ enum FileOp {
 
 fn collect_recent_file_ops(
     repo: &Repository,
+    head_commit_id: Oid,
     last_commit_id: Oid,
-) -> HashMap<PathBuf, FileOp> {
+) -> Result<HashMap<PathBuf, FileOp>, git2::Error> {
     use git2::Delta;
 
     // Iterate over commit history after `last_commit_id` to collect recent file operations
     let mut recent_ops: HashMap<PathBuf, FileOp> = HashMap::new();
-    let mut revwalk = repo.revwalk().unwrap();
-    revwalk.set_sorting(git2::Sort::TOPOLOGICAL).unwrap();
-    revwalk.push_range(&format!("{}..HEAD", last_commit_id)).unwrap();
-    for oid in revwalk {
-        let oid = oid.unwrap();
-        let commit = repo.find_commit(oid).unwrap();
+    for commit in history::walk_commits(repo, head_commit_id, Some(last_commit_id), false)? {
+        let commit = commit?;
         tracing::debug!("{:?}", commit);
 
-        let tree = commit.tree().unwrap();
+        let tree = commit.tree()?;
         for parent in commit.parents() {
-            let parent_tree = parent.tree().unwrap();
-            let diff = repo.diff_tree_to_tree(Some(&parent_tree), Some(&tree), None).unwrap();
+            let parent_tree = parent.tree()?;
+            let diff = history::tree_changes(repo, Some(&parent_tree), &tree)?;
             for delta in diff.deltas() {
                 match delta.status() {
                     Delta::Added | Delta::Modified | Delta::Copied => {
@@ -387,7 +1169,7 @@ fn collect_recent_file_ops(
         }
     }
 
-    recent_ops
+    Ok(recent_ops)
 }
 
 fn guess_mime_from_path<P: AsRef<Path>>(path: P) -> String {
@@ -443,7 +1225,7 @@ async fn rebuild_entries_cache(
             for parent in commit.parents() {
                 // FIXME: We assume there were no conflict in the case of multiple parents
                 let parent_tree = parent.tree()?;
-                let diff = repo.diff_tree_to_tree(Some(&parent_tree), Some(&tree), None)?;
+                let diff = history::tree_changes(&repo, Some(&parent_tree), &tree)?;
                 for delta in diff.deltas() {
                     use git2::Delta;
                     match delta.status() {
@@ -529,6 +1311,32 @@ fn is_ancestor(
     Ok(false)
 }
 
+/// Whether `new` is an amended `old`, changing again every path `old` changed.
+///
+/// The entries of `old` can then be updated with the changes of `new` like those of an ancestor.
+fn amends(
+    repo: &Repository,
+    old: Oid,
+    new: Oid,
+) -> Result<bool> {
+    let old = repo.find_commit(old)?;
+    let new = repo.find_commit(new)?;
+    if old.parent_count() != 1 || !old.parent_ids().eq(new.parent_ids()) {
+        return Ok(false);
+    }
+
+    let parent_tree = old.parent(0)?.tree()?;
+    let changed_paths = |commit: &git2::Commit| -> Result<HashSet<PathBuf>> {
+        let diff = repo.diff_tree_to_tree(Some(&parent_tree), Some(&commit.tree()?), None)?;
+        Ok(diff.deltas()
+            .flat_map(|delta| [delta.old_file().path(), delta.new_file().path()])
+            .flatten()
+            .map(Path::to_path_buf)
+            .collect())
+    };
+    Ok(changed_paths(&old)?.is_subset(&changed_paths(&new)?))
+}
+
 async fn update_entries_cache(
     conn: &mut SqliteConnection,
     repo: Arc<Mutex<Repository>>,
@@ -552,7 +1360,7 @@ async fn update_entries_cache(
         .context("Failed to copy the last entries with the new commit ID")?;
 
     // Iterate over recent commit history to collect operations on files
-    let recent_ops = collect_recent_file_ops(&*repo.lock().unwrap(), last_commit_id);
+    let recent_ops = collect_recent_file_ops(&repo.lock().unwrap(), head_commit_id, last_commit_id)?;
 
     // Update entries based on recent file operations
     for (path, op) in recent_ops {
@@ -618,23 +1426,43 @@ async fn update_entries_cache(
 
 async fn get_notes(
     extract::State(state): extract::State<AppState>,
-) -> Json<Vec<ListEntry>> {
+    extract::Extension(principal): extract::Extension<Principal>,
+) -> Result<Json<Vec<ListEntry>>, AppError> {
     tracing::debug!("get_notes");
-    Json(state.get_entries(None).await.unwrap().1)
+    Ok(Json(state.get_entries(None, &principal.acl).await?.1))
 }
 
 async fn find_entry_blob(
     state: &AppState,
     path: &str,
+    access: &PathAccess,
+) -> Option<(Oid, Vec<u8>)> {
+    find_entry_blob_at(state, path, access, None).await
+}
+
+/// Content of `path` at a commit, or HEAD if `None`, along with the commit ID.
+async fn find_entry_blob_at(
+    state: &AppState,
+    path: &str,
+    access: &PathAccess,
+    commit_id: Option<Oid>,
 ) -> Option<(Oid, Vec<u8>)> {
-    // Search an index of HEAD for the given path
+    // Paths the user cannot read are treated as missing
+    if !access.can_read(path) {
+        return None;
+    }
+
+    // Search an index of the commit for the given path
     let (oid, entry) = {
         let repo = state.repo.lock().unwrap();
 
-        // Build an in-memory index of HEAD
-        let head_ref = repo.head().ok()?;
-        let head_oid = head_ref.target()?;
-        let head_tree = head_ref.peel_to_tree().ok()?;
+        // Build an in-memory index of the commit
+        let commit = match commit_id {
+            Some(commit_id) => repo.find_commit(commit_id).ok()?,
+            None => repo.head().ok()?.peel_to_commit().ok()?,
+        };
+        let head_oid = commit.id();
+        let head_tree = commit.tree().ok()?;
 
         let mut index = Index::new().ok()?;
         index.read_tree(&head_tree).ok()?;
@@ -668,176 +1496,212 @@ fn content_response(content: Vec<u8>, path: &Path) -> Response {
 }
 
 async fn get_notes_path(
-    extract::Path(path): extract::Path<String>,
+    AppPath(path): AppPath<String>,
     extract::State(state): extract::State<AppState>,
-) -> Response {
+    extract::Extension(principal): extract::Extension<Principal>,
+) -> Result<Response, AppError> {
     tracing::debug!("get_notes_path");
 
-    if let Some((_, content)) = find_entry_blob(&state, &path).await {
-        content_response(content, path.as_ref())
-    }
-    else {
-        StatusCode::NOT_FOUND.into_response()
-    }
+    let (_, content) = find_entry_blob(&state, &path, &principal.acl).await.ok_or(AppError::NotFound)?;
+    Ok(content_response(content, path.as_ref()))
 }
 
 async fn put_notes_path(
-    extract::Path(path): extract::Path<String>,
+    AppPath(path): AppPath<RepoPath>,
     extract::State(state): extract::State<AppState>,
-    Json(note_save): Json<NoteSave>,
-) -> Response {
+    extract::Extension(principal): extract::Extension<Principal>,
+    headers: HeaderMap,
+    AppJson(note_save): AppJson<NoteSave>,
+) -> Result<Response, AppError> {
     tracing::debug!("put_notes_path");
     tracing::debug!("{:?}", note_save);
 
+    if !principal.acl.can_write(&path) {
+        return Err(AppError::Forbidden);
+    }
+
     match note_save {
-        NoteSave::Save { content, message } => {
+        NoteSave::Save { content, message, base } => {
             let repo = state.repo.lock().unwrap();
 
-            let head = repo.head().unwrap();
-            let head_tree = head.peel_to_tree().unwrap();
-            let head_commit = head.peel_to_commit().unwrap();
-
-            let mut index = Index::new().unwrap();
-            index.read_tree(&head_tree).unwrap();
-
-            let blob_oid = repo.blob(content.as_bytes()).unwrap();
-            let entry = IndexEntry {
-                ctime: IndexTime::new(0, 0),
-                mtime: IndexTime::new(0, 0),
-                dev: 0,
-                ino: 0,
-                mode: 0o100644,
-                uid: 0,
-                gid: 0,
-                file_size: 0,
-                id: blob_oid,
-                flags: 0,
-                flags_extended: 0,
-                path: path.as_bytes().into(),
+            check_if_match(&repo, &headers, &path)?;
+
+            let head_commit = head_commit(&repo)?;
+            let mut index = index_of(head_commit.as_ref())?;
+
+            // Merge with changes made since the version the client started from
+            let current = index.get_path(Path::new(path.as_str()), 0).map(|entry| entry.id);
+            let content = match base.as_deref().map(Oid::from_str) {
+                None => content,
+                Some(Ok(base_oid)) if current == Some(base_oid) => content,
+                Some(Ok(base_oid)) => {
+                    let base_blob = find_note_base(&repo, head_commit.as_ref(), &path, base_oid)?;
+                    let base_content = String::from_utf8_lossy(base_blob.content());
+                    let current_content = match current {
+                        Some(oid) => Some(String::from_utf8_lossy(repo.find_blob(oid)?.content()).into_owned()),
+                        None => None,
+                    };
+                    match merge_note(&base_content, &content, current_content.as_deref().unwrap_or("")) {
+                        Ok(merged) => {
+                            tracing::info!("Merged a save of {} based on {} with {:?}", path, base_oid, current);
+                            merged
+                        },
+                        Err(hunks) => {
+                            let conflict = MergeConflict {
+                                current: NoteVersion {
+                                    commit_id: head_commit.as_ref().map(|commit| commit.id().to_string()),
+                                    blob_id: current.map(|oid| oid.to_string()),
+                                    content: current_content,
+                                },
+                                hunks,
+                            };
+                            return Err(AppError::conflict(format!("{} cannot be merged", path), conflict));
+                        },
+                    }
+                },
+                Some(Err(e)) => return Err(AppError::BadRequest(format!("invalid base: {}", e.message()))),
             };
-            index.add(&entry).unwrap();
 
-            let tree_oid = index.write_tree_to(&repo).unwrap();
-            let tree = repo.find_tree(tree_oid).unwrap();
+            let blob_oid = repo.blob(content.as_bytes())?;
+            index.add(&blob_index_entry(&path, blob_oid))?;
+
+            let tree_oid = index.write_tree_to(&repo)?;
+            let tree = repo.find_tree(tree_oid)?;
+
+            // Successive saves may be coalesced into the commit of the previous one
+            let author = principal.signature()?;
+            let now = time::Instant::now();
+            let commit_oid = match &head_commit {
+                Some(head_commit) => {
+                    let amend = state.autosave.lock().unwrap().can_amend(head_commit.id(), &principal.user, &path, now)
+                        && !is_pushed(&repo, head_commit.id())?;
+                    let commit_oid = if amend {
+                        amend_head(&repo, state.signer.as_deref(), &author, &message, &tree, head_commit)?
+                    }
+                    else {
+                        commit_to_head(&repo, state.signer.as_deref(), &author, &message, &tree, &[head_commit])?
+                    };
+                    state.autosave.lock().unwrap().record(commit_oid, &principal.user, &path, now, amend);
+                    commit_oid
+                },
+                None => commit_to_head(&repo, state.signer.as_deref(), &author, &message, &tree, &[])?,
+            };
 
-            let signature = repo.signature().unwrap();
-            repo.commit(
-                Some("HEAD"),
-                &signature,
-                &signature,
-                &message,
-                &tree,
-                &[&head_commit],
-            ).unwrap();
-            Json(&true).into_response()
+            // Clients sending a base get the saved version, which may include merged changes
+            if base.is_some() {
+                let version = NoteVersion {
+                    commit_id: Some(commit_oid.to_string()),
+                    blob_id: Some(blob_oid.to_string()),
+                    content: Some(content),
+                };
+                return Ok((Extension(CommitCreated(commit_oid)), Json(version)).into_response());
+            }
+            Ok((Extension(CommitCreated(commit_oid)), Json(&true)).into_response())
         },
         NoteSave::Rename { from } => {
-            let found = {
-                let repo = state.repo.lock().unwrap();
+            let from = RepoPath::new(&from)?;
+
+            // The source path must be accessible as well as the destination
+            if !principal.permits(&Method::PUT, "/notes/*path", &from) || !principal.acl.can_write(&from) {
+                return Err(AppError::Forbidden);
+            }
 
-                let head = repo.head().unwrap();
-                let head_tree = head.peel_to_tree().unwrap();
+            let repo = state.repo.lock().unwrap();
 
-                let mut index = Index::new().unwrap();
-                index.read_tree(&head_tree).unwrap();
+            let head_commit = head_commit(&repo)?.ok_or(AppError::NotFound)?;
+            let mut index = index_of(Some(&head_commit))?;
 
-                index.iter().find(|entry| std::str::from_utf8(&entry.path).unwrap() == from)
-            };
-            if let Some(mut entry) = found {
-                let repo = state.repo.lock().unwrap();
-
-                let head = repo.head().unwrap();
-                let head_tree = head.peel_to_tree().unwrap();
-                let head_commit = head.peel_to_commit().unwrap();
-
-                let mut index = Index::new().unwrap();
-                index.read_tree(&head_tree).unwrap();
-
-                let from = std::str::from_utf8(&entry.path).unwrap();
-                index.remove(from.as_ref(), 0).unwrap();
-
-                let message = format!("Rename {} to {}", &from, &path);
-                entry.path = path.as_bytes().into();
-                index.add(&entry).unwrap();
-
-                let tree_oid = index.write_tree_to(&repo).unwrap();
-                let tree = repo.find_tree(tree_oid).unwrap();
-
-                let signature = repo.signature().unwrap();
-                repo.commit(
-                    Some("HEAD"),
-                    &signature,
-                    &signature,
-                    &message,
-                    &tree,
-                    &[&head_commit],
-                ).unwrap();
-                Json(&true).into_response()
+            // A directory is renamed with everything under it
+            let renamed = rename_in_index(&mut index, &from, &path)?;
+            if renamed.is_empty() {
+                return Err(AppError::NotFound);
             }
-            else {
-                StatusCode::NOT_FOUND.into_response()
+            for renamed in &renamed {
+                if !principal.acl.can_write(&renamed.from) || !principal.acl.can_write(&renamed.to) {
+                    return Err(AppError::Forbidden);
+                }
+                check_if_match(&repo, &headers, &renamed.from)?;
             }
+
+            let tree_oid = index.write_tree_to(&repo)?;
+            let tree = repo.find_tree(tree_oid)?;
+
+            let author = principal.signature()?;
+            let commit_oid = commit_to_head(
+                &repo,
+                state.signer.as_deref(),
+                &author,
+                &format!("Rename {} to {}", &from, &path),
+                &tree,
+                &[&head_commit],
+            )?;
+            Ok((Extension(CommitCreated(commit_oid)), Json(renamed)).into_response())
         },
     }
 }
 
 async fn delete_notes_path(
-    extract::Path(path): extract::Path<String>,
+    AppPath(path): AppPath<RepoPath>,
     extract::State(state): extract::State<AppState>,
-) -> Response {
+    extract::Extension(principal): extract::Extension<Principal>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
     tracing::debug!("delete_notes_path");
 
-    let found = {
-        let repo = state.repo.lock().unwrap();
-
-        let head = repo.head().unwrap();
-        let head_tree = head.peel_to_tree().unwrap();
-
-        let mut index = Index::new().unwrap();
-        index.read_tree(&head_tree).unwrap();
+    if !principal.acl.can_write(&path) {
+        return Err(AppError::Forbidden);
+    }
 
-        index.iter().find(|entry| std::str::from_utf8(&entry.path).unwrap() == path)
-    };
-    if let Some(entry) = found {
+    let (commit_oid, deleted) = {
         let repo = state.repo.lock().unwrap();
 
-        let head = repo.head().unwrap();
-        let head_tree = head.peel_to_tree().unwrap();
-        let head_commit = head.peel_to_commit().unwrap();
+        let head_commit = head_commit(&repo)?.ok_or(AppError::NotFound)?;
+        let mut index = index_of(Some(&head_commit))?;
 
-        let mut index = Index::new().unwrap();
-        index.read_tree(&head_tree).unwrap();
-
-        let path = std::str::from_utf8(&entry.path).unwrap();
-        index.remove(path.as_ref(), 0).unwrap();
+        // A directory is deleted with everything under it
+        let deleted = remove_from_index(&mut index, &path)?;
+        if deleted.is_empty() {
+            return Err(AppError::NotFound);
+        }
+        for trash::DeletedPath { path: deleted_path, .. } in &deleted {
+            if !principal.acl.can_write(deleted_path) {
+                return Err(AppError::Forbidden);
+            }
+            check_if_match(&repo, &headers, deleted_path)?;
+        }
 
-        let tree_oid = index.write_tree_to(&repo).unwrap();
-        let tree = repo.find_tree(tree_oid).unwrap();
+        let tree_oid = index.write_tree_to(&repo)?;
+        let tree = repo.find_tree(tree_oid)?;
 
-        let signature = repo.signature().unwrap();
-        repo.commit(
-            Some("HEAD"),
-            &signature,
-            &signature,
+        let author = principal.signature()?;
+        let commit_oid = commit_to_head(
+            &repo,
+            state.signer.as_deref(),
+            &author,
             &format!("Delete {}", &path),
             &tree,
             &[&head_commit],
-        ).unwrap();
-        Json(&true).into_response()
-    }
-    else {
-        StatusCode::NOT_FOUND.into_response()
+        )?;
+        (commit_oid, deleted)
+    };
+
+    // The deletion is committed either way, and can still be undone through git
+    if let Err(e) = state.record_trash(commit_oid, &principal.user, &deleted).await {
+        tracing::error!("failed to record deleted paths in the trash: {:?}", e);
     }
+
+    let deleted: Vec<String> = deleted.into_iter().map(|deleted| deleted.path).collect();
+    Ok((Extension(CommitCreated(commit_oid)), Json(deleted)).into_response())
 }
 
-async fn serve_image_content(content: Vec<u8>, path: &Path) -> Response {
+async fn serve_image_content(content: Vec<u8>, path: &Path) -> Result<Response, AppError> {
     // Build cache path
     let cache_root = PathBuf::from(env::var("MORIED_IMAGE_CACHE_DIR")
-        .expect("MORIED_IMAGE_CACHE_DIR must be set"));
+        .context("MORIED_IMAGE_CACHE_DIR must be set")?);
     let hash = Sha1::digest(&content);
     let mut buf = [0u8; 40];
-    let hex = base16ct::lower::encode_str(&hash, &mut buf).unwrap();
-    let cache_path = cache_root.join(&hex);
+    let hex = base16ct::lower::encode_str(&hash, &mut buf).map_err(|e| anyhow::anyhow!("{}", e))?;
+    let cache_path = cache_root.join(hex);
 
     // If we already have a webp in cache, serve it
     if let Ok(meta) = tokio::fs::metadata(&cache_path).await {
@@ -846,17 +1710,17 @@ async fn serve_image_content(content: Vec<u8>, path: &Path) -> Response {
                 let mut res = cached.into_response();
                 res.headers_mut().insert(
                     header::CONTENT_TYPE,
-                    "image/webp".parse().unwrap(),
+                    HeaderValue::from_static("image/webp"),
                 );
-                return res;
+                return Ok(res);
             }
         }
     }
 
     // Otherwise write a temp file, call `convert`, cache & serve
-    let tmp_dir = tempdir().unwrap();
-    let tmp_file_path = tmp_dir.path().join(path.file_name().unwrap());
-    tokio::fs::write(&tmp_file_path, &content).await.unwrap();
+    let tmp_dir = tempdir()?;
+    let tmp_file_path = tmp_dir.path().join(path.file_name().unwrap_or(OsStr::new("image")));
+    tokio::fs::write(&tmp_file_path, &content).await?;
 
     let output = Command::new("convert")
         .arg(&tmp_file_path)
@@ -865,70 +1729,157 @@ async fn serve_image_content(content: Vec<u8>, path: &Path) -> Response {
         .arg("webp:-")
         .stdout(Stdio::piped())
         .spawn()
-        .unwrap()
+        .context("Failed to execute convert")?
         .wait_with_output()
-        .await
-        .unwrap();
+        .await?;
 
     if output.status.success() {
         if let Some(parent) = cache_path.parent() {
-            tokio::fs::create_dir_all(parent).await.unwrap();
+            tokio::fs::create_dir_all(parent).await?;
         }
-        tokio::fs::write(&cache_path, &output.stdout).await.unwrap();
+        tokio::fs::write(&cache_path, &output.stdout).await?;
 
         let mut res = output.stdout.into_response();
         res.headers_mut().insert(
             header::CONTENT_TYPE,
-            "image/webp".parse().unwrap(),
+            HeaderValue::from_static("image/webp"),
         );
-        res
+        Ok(res)
     } else {
         // Fallback to original image bytes + mime
-        content_response(content, &path)
+        Ok(content_response(content, path))
     }
 }
 
 async fn get_files_path(
-    extract::Path(path): extract::Path<String>,
+    AppPath(path): AppPath<String>,
     extract::State(state): extract::State<AppState>,
-) -> Response {
+    extract::Extension(principal): extract::Extension<Principal>,
+) -> Result<Response, AppError> {
     tracing::debug!("get_files_path");
 
-    if let Some((_, content)) = find_entry_blob(&state, &path).await {
-        match mime_guess::from_path::<&Path>(path.as_ref()).first() {
-            Some(mime) if mime.type_() == "image" => {
-                serve_image_content(content, path.as_ref()).await
-            },
-            _ => content_response(content, path.as_ref()),
-        }
+    let (_, content) = find_entry_blob(&state, &path, &principal.acl).await.ok_or(AppError::NotFound)?;
+    match mime_guess::from_path::<&Path>(path.as_ref()).first() {
+        Some(mime) if mime.type_() == "image" => {
+            serve_image_content(content, path.as_ref()).await
+        },
+        _ => Ok(content_response(content, path.as_ref())),
     }
-    else {
-        StatusCode::NOT_FOUND.into_response()
+}
+
+/// Key for signing share links, derived from `MORIED_SECRET` so that it differs from the key of access tokens.
+fn share_link_key() -> impl AsRef<[u8]> {
+    let secret = env::var("MORIED_SECRET").unwrap();
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(b"moried share link");
+    mac.finalize().into_bytes()
+}
+
+/// Signature of a share link, which covers everything the link gives access to.
+fn share_link_signature(share: &ShareLink) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(share_link_key().as_ref()).unwrap();
+    mac.update(share.id.as_bytes());
+    mac.update(b"\0");
+    mac.update(share.path.as_bytes());
+    mac.update(b"\0");
+    mac.update(share.blob_id.as_deref().unwrap_or("").as_bytes());
+    mac.update(b"\0");
+    mac.update(share.expires_at.timestamp().to_string().as_bytes());
+    mac
+}
+
+/// URL of a share link relative to `MORIED_ROOT_PATH`.
+fn share_link_url(share: &ShareLink) -> String {
+    let signature = share_link_signature(share).finalize().into_bytes();
+    let mut buf = [0u8; 64];
+    let signature = base16ct::lower::encode_str(&signature, &mut buf).unwrap();
+    format!("share/{}?exp={}&sig={}", share.id, share.expires_at.timestamp(), signature)
+}
+
+/// Serve a shared note or file without authentication.
+async fn get_share_id(
+    AppPath(id): AppPath<String>,
+    AppQuery(query): AppQuery<ShareQuery>,
+    extract::State(state): extract::State<AppState>,
+) -> Result<Response, AppError> {
+    tracing::debug!("get_share_id");
+
+    let share = state.find_share_link(&id).await?.ok_or(AppError::NotFound)?;
+
+    // Verify the signature before telling anything about the link
+    let mut buf = [0u8; 32];
+    let signature_is_valid = base16ct::lower::decode(&query.sig, &mut buf)
+        .is_ok_and(|signature| share_link_signature(&share).verify_slice(signature).is_ok());
+    if !signature_is_valid || query.exp != share.expires_at.timestamp() {
+        return Err(AppError::NotFound);
+    }
+    if share.revoked_at.is_some() || share.expires_at <= Utc::now() {
+        return Err(AppError::Gone);
+    }
+    // Links stop working when their creator's account is disabled or can no longer read the path
+    let creator = match state.find_user(&share.created_by).await? {
+        Some(user) if !user.disabled => user,
+        _ => return Err(AppError::Gone),
+    };
+    let acl = state.path_access(&creator, None).await?;
+    if !acl.can_read(&share.path) {
+        return Err(AppError::Gone);
+    }
+
+    let content = match &share.blob_id {
+        Some(blob_id) => {
+            let repo = state.repo.lock().unwrap();
+            Oid::from_str(blob_id).ok()
+                .and_then(|oid| repo.find_blob(oid).ok())
+                .map(|blob| Vec::from(blob.content()))
+        },
+        None => find_entry_blob(&state, &share.path, &acl).await.map(|(_, content)| content),
+    };
+    let content = content.ok_or(AppError::NotFound)?;
+
+    let path: &Path = share.path.as_ref();
+    match mime_guess::from_path(path).first() {
+        Some(mime) if mime.type_() == "image" => serve_image_content(content, path).await,
+        _ => Ok(content_response(content, path)),
     }
 }
 
 async fn post_files(
     extract::State(state): extract::State<AppState>,
-    mut multipart: extract::Multipart,
-) -> Response {
+    extract::Extension(principal): extract::Extension<Principal>,
+    headers: HeaderMap,
+    multipart: Result<extract::Multipart, extract::multipart::MultipartRejection>,
+) -> Result<Response, AppError> {
     tracing::debug!("post_files_path");
 
     // Create a blob for each part (file) in the form data
+    let mut multipart = multipart?;
     let mut files = Vec::new();
     let mut result = Vec::new();
-    while let Some(field) = multipart.next_field().await.unwrap() {
+    while let Some(field) = multipart.next_field().await? {
         tracing::debug!("{:?}", field);
 
-        let uuid = field.name().unwrap().to_owned();
-        let filename = field.file_name().unwrap().as_bytes().to_vec();
+        let uuid = field.name()
+            .ok_or_else(|| AppError::BadRequest("form field without a name".to_owned()))?
+            .to_owned();
+        // Missing and non-UTF-8 file names are rejected as empty
+        let filename = RepoPath::new(field.file_name().unwrap_or(""))?;
+
+        if !principal.permits(&Method::POST, "/files", &filename) || !principal.acl.can_write(&filename) {
+            return Err(AppError::Forbidden);
+        }
+        // A single version cannot be expected of several files
+        if !files.is_empty() && headers.contains_key(header::IF_MATCH) {
+            return Err(AppError::BadRequest("If-Match is allowed only for uploading a single file".to_owned()));
+        }
 
         let blob_oid = {
-            let data = field.bytes().await.unwrap();
+            let data = field.bytes().await?;
 
             let repo = state.repo.lock().unwrap();
-            let mut writer = repo.blob_writer(None).unwrap();
-            writer.write_all(&data).unwrap();
-            writer.commit().unwrap()
+            let mut writer = repo.blob_writer(None)?;
+            writer.write_all(&data)?;
+            writer.commit()?
         };
 
         files.push((filename, blob_oid));
@@ -938,51 +1889,37 @@ async fn post_files(
     // Commit
     let repo = state.repo.lock().unwrap();
 
-    let head = repo.head().unwrap();
-    let head_tree = head.peel_to_tree().unwrap();
-    let head_commit = head.peel_to_commit().unwrap();
+    if let [(path, _)] = files.as_slice() {
+        check_if_match(&repo, &headers, path)?;
+    }
 
-    let mut index = Index::new().unwrap();
-    index.read_tree(&head_tree).unwrap();
+    let head_commit = head_commit(&repo)?;
+    let mut index = index_of(head_commit.as_ref())?;
 
     let count = files.len();
     for (path, blob_oid) in files {
-        let entry = IndexEntry {
-            ctime: IndexTime::new(0, 0),
-            mtime: IndexTime::new(0, 0),
-            dev: 0,
-            ino: 0,
-            mode: 0o100644,
-            uid: 0,
-            gid: 0,
-            file_size: 0,
-            id: blob_oid,
-            flags: 0,
-            flags_extended: 0,
-            path: path,
-        };
-        index.add(&entry).unwrap();
+        index.add(&blob_index_entry(&path, blob_oid))?;
     }
 
-    let tree_oid = index.write_tree_to(&repo).unwrap();
-    let tree = repo.find_tree(tree_oid).unwrap();
+    let tree_oid = index.write_tree_to(&repo)?;
+    let tree = repo.find_tree(tree_oid)?;
 
-    let signature = repo.signature().unwrap();
-    repo.commit(
-        Some("HEAD"),
-        &signature,
-        &signature,
+    let author = principal.signature()?;
+    let commit_oid = commit_to_head(
+        &repo,
+        state.signer.as_deref(),
+        &author,
         &format!("Upload {} files", count),
         &tree,
-        &[&head_commit],
-    ).unwrap();
+        &head_commit.iter().collect::<Vec<_>>(),
+    )?;
 
-    Json(result).into_response()
+    Ok((Extension(CommitCreated(commit_oid)), Json(result)).into_response())
 }
 
 fn get_frontmatter_node(node: &markdown::mdast::Node) -> Option<&markdown::mdast::Node> {
     use markdown::mdast::Node;
-    node.children().and_then(|children| children.get(0)).and_then(|first_child_node| {
+    node.children().and_then(|children| children.first()).and_then(|first_child_node| {
         match first_child_node {
             Node::Yaml(_) | Node::Toml(_) => {
                 Some(first_child_node)
@@ -1047,22 +1984,18 @@ fn extract_metadata(blob: &[u8]) -> (Option<serde_yaml::Value>, Option<String>)
 
 /// Search notes for a given query with `git grep`.
 pub async fn post_notes(
-    Json(query): Json<GrepQuery>,
-) -> impl IntoResponse {
-    let git_dir = env::var("MORIED_GIT_DIR").unwrap();
-    match grep_bare_repo(&git_dir, &query.pattern, "HEAD").await {
-        Ok(matches) => Json(matches).into_response(),
-        Err(err) => (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Error: {}", err),
-        ).into_response(),
-    }
+    extract::Extension(principal): extract::Extension<Principal>,
+    AppJson(query): AppJson<GrepQuery>,
+) -> Result<Json<Vec<GrepMatch>>, AppError> {
+    let git_dir = env::var("MORIED_GIT_DIR").context("MORIED_GIT_DIR must be set")?;
+    Ok(Json(grep_bare_repo(&git_dir, &query.pattern, "HEAD", &principal.acl).await?))
 }
 
 pub async fn grep_bare_repo(
     git_dir: &str,
     pattern: &str,
     revision: &str,
+    access: &PathAccess,
 ) -> anyhow::Result<Vec<models::GrepMatch>> {
     let output = Command::new("git")
         .arg("-C")
@@ -1094,6 +2027,9 @@ pub async fn grep_bare_repo(
             Some(f) => f.strip_prefix(&format!("{revision}:")).unwrap_or(f),
             None => continue,
         };
+        if !access.can_read(file) {
+            continue;
+        }
 
         let line_no = match parts.next().and_then(|s| s.parse::<usize>().ok()) {
             Some(n) => n,
@@ -1118,6 +2054,7 @@ pub async fn grep_bare_repo(
 mod v2 {
     use super::*;
     use std::env;
+    use axum::extract::FromRequest;
 
     #[derive(Deserialize, Serialize)]
     pub struct AssessmentRequest {
@@ -1171,7 +2108,7 @@ mod v2 {
 
     pub async fn post_assess_task(
         extract::State(state): extract::State<AppState>,
-        Json(request): Json<AssessmentRequest>,
+        AppJson(request): AppJson<AssessmentRequest>,
     ) -> Result<Json<AssessmentResponse>, AppError> {
         // Create cache key from request data
         let request_json = serde_json::to_string(&request)
@@ -1316,28 +2253,30 @@ Important:
             .json(&openai_request)
             .send()
             .await
-            .context("Failed to send request to OpenAI")?;
+            .context("Failed to send request to OpenAI")
+            .map_err(AppError::Upstream)?;
 
         if !response.status().is_success() {
             let status = response.status();
             let error_text = response.text().await.unwrap_or_default();
-            return Err(anyhow::anyhow!("OpenAI API error {}: {}", status, error_text).into());
+            return Err(AppError::Upstream(anyhow::anyhow!("OpenAI API error {}: {}", status, error_text)));
         }
 
         let openai_response: OpenAIResponse = response
             .json()
             .await
-            .context("Failed to parse OpenAI response")?;
+            .context("Failed to parse OpenAI response")
+            .map_err(AppError::Upstream)?;
 
         let content = openai_response
             .choices
-            .first()
-            .and_then(|choice| Some(&choice.message.content))
-            .ok_or_else(|| anyhow::anyhow!("No response from OpenAI"))?;
+            .first().map(|choice| &choice.message.content)
+            .ok_or_else(|| AppError::Upstream(anyhow::anyhow!("No response from OpenAI")))?;
 
         // Parse the JSON content from OpenAI response
         let assessment: AssessmentResponse = serde_json::from_str(content)
-            .context("Failed to parse OpenAI JSON response")?;
+            .context("Failed to parse OpenAI JSON response")
+            .map_err(AppError::Upstream)?;
 
         // Cache the response
         let response_json = serde_json::to_string(&assessment)
@@ -1370,56 +2309,206 @@ Important:
         extract::State(state): extract::State<AppState>,
     ) -> Result<Json<String>, AppError> {
         let repo = state.repo.lock().unwrap();
-        let head = repo.head()?;
-        let commit = head.peel_to_commit()?;
-        let commit_id = commit.id();
-        Ok(Json(commit_id.to_string()))
+        let commit = head_commit(&repo)?.ok_or(AppError::NotFound)?;
+        Ok(Json(commit.id().to_string()))
+    }
+
+    /// Apply several operations as a single commit.
+    ///
+    /// The body is either the JSON `NewCommit`, or a multipart form with the
+    /// JSON in a `commit` field and the files referenced by `upload` operations
+    /// in fields of their own. Either all operations succeed or nothing is
+    /// committed.
+    pub async fn post_commits(
+        extract::State(state): extract::State<AppState>,
+        extract::Extension(principal): extract::Extension<Principal>,
+        headers: HeaderMap,
+        req: Request<Body>,
+    ) -> Result<Response, AppError> {
+        tracing::debug!("v2::post_commits");
+
+        let is_multipart = headers.get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("multipart/form-data"));
+        let mut new_commit = None;
+        let mut uploads = HashMap::new();
+        if is_multipart {
+            let mut multipart = extract::Multipart::from_request(req, &state).await?;
+            while let Some(field) = multipart.next_field().await? {
+                let name = field.name().unwrap_or("").to_owned();
+                let data = field.bytes().await?;
+                if name == "commit" {
+                    let commit = serde_json::from_slice::<NewCommit>(&data)
+                        .map_err(|e| AppError::BadRequest(format!("invalid commit field: {}", e)))?;
+                    new_commit = Some(commit);
+                }
+                else {
+                    let repo = state.repo.lock().unwrap();
+                    let mut writer = repo.blob_writer(None)?;
+                    writer.write_all(&data)?;
+                    uploads.insert(name, writer.commit()?);
+                }
+            }
+        }
+        else {
+            let AppJson(commit) = AppJson::<NewCommit>::from_request(req, &state).await?;
+            new_commit = Some(commit);
+        }
+        let new_commit = new_commit.ok_or_else(|| AppError::BadRequest("missing commit field".to_owned()))?;
+        if new_commit.operations.is_empty() {
+            return Err(AppError::BadRequest("no operations to commit".to_owned()));
+        }
+        if headers.contains_key(header::IF_MATCH) {
+            return Err(AppError::BadRequest("If-Match is not allowed, set if_match on the operations instead".to_owned()));
+        }
+
+        let (commit_oid, paths, deleted) = {
+            let repo = state.repo.lock().unwrap();
+
+            let head_commit = head_commit(&repo)?;
+            let mut index = index_of(head_commit.as_ref())?;
+
+            // Paths touched, with directories expanded to the files under them
+            let mut paths = Vec::new();
+            let mut deleted = Vec::new();
+            // Versions the operations expect at HEAD, and paths they would overwrite without expecting any
+            let mut expected = Vec::new();
+            let mut existing = Vec::new();
+            for operation in &new_commit.operations {
+                match operation {
+                    CommitOperation::Put { path, content, if_match } => {
+                        match if_match {
+                            Some(if_match) => expected.push((path.to_string(), if_match)),
+                            None => existing.extend(index_paths_under(&index, path)),
+                        }
+                        let blob_oid = repo.blob(content.as_bytes())?;
+                        index.add(&blob_index_entry(path, blob_oid))?;
+                        paths.push(path.to_string());
+                    },
+                    CommitOperation::Upload { path, upload, if_match } => {
+                        match if_match {
+                            Some(if_match) => expected.push((path.to_string(), if_match)),
+                            None => existing.extend(index_paths_under(&index, path)),
+                        }
+                        let blob_oid = uploads.get(upload)
+                            .ok_or_else(|| AppError::BadRequest(format!("no upload named {}", upload)))?;
+                        index.add(&blob_index_entry(path, *blob_oid))?;
+                        paths.push(path.to_string());
+                    },
+                    CommitOperation::Delete { path, if_match } => {
+                        let removed = remove_from_index(&mut index, path)?;
+                        if removed.is_empty() {
+                            return Err(AppError::NotFound);
+                        }
+                        if let Some(if_match) = if_match {
+                            expected.extend(removed.iter().map(|removed| (removed.path.clone(), if_match)));
+                        }
+                        paths.extend(removed.iter().map(|removed| removed.path.clone()));
+                        deleted.extend(removed);
+                    },
+                    CommitOperation::Rename { from, to, if_match } => {
+                        existing.extend(index_paths_under(&index, to));
+                        let renamed = rename_in_index(&mut index, from, to)?;
+                        if renamed.is_empty() {
+                            return Err(AppError::NotFound);
+                        }
+                        if let Some(if_match) = if_match {
+                            expected.extend(renamed.iter().map(|renamed| (renamed.from.clone(), if_match)));
+                        }
+                        paths.extend(renamed.into_iter().flat_map(|renamed| [renamed.from, renamed.to]));
+                    },
+                }
+            }
+            paths.sort();
+            paths.dedup();
+
+            // Every path touched must be writable
+            if !paths.iter().all(|path| principal.permits(&Method::POST, "/v2/commits", path) && principal.acl.can_write(path)) {
+                return Err(AppError::Forbidden);
+            }
+            if !existing.is_empty() {
+                existing.sort();
+                existing.dedup();
+                return Err(AppError::conflict("some of the paths exist", ConflictingPaths { paths: existing }));
+            }
+            for (path, if_match) in &expected {
+                check_version(&repo, if_match, path)?;
+            }
+
+            let tree_oid = index.write_tree_to(&repo)?;
+            let tree = repo.find_tree(tree_oid)?;
+
+            let author = principal.signature()?;
+            let parents: Vec<_> = head_commit.iter().collect();
+            let commit_oid = commit_to_head(&repo, state.signer.as_deref(), &author, &new_commit.message, &tree, &parents)?;
+            tracing::info!("User '{}' committed {} operations as {}", principal.user, new_commit.operations.len(), commit_oid);
+
+            // Deleted paths put back by later operations are not in the trash
+            deleted.retain(|deleted| index.get_path(Path::new(&deleted.path), 0).is_none());
+            (commit_oid, paths, deleted)
+        };
+
+        if let Err(e) = state.record_trash(commit_oid, &principal.user, &deleted).await {
+            tracing::error!("failed to record deleted paths in the trash: {:?}", e);
+        }
+
+        let created = CreatedCommit {
+            commit_id: commit_oid.to_string(),
+            paths,
+        };
+        Ok((StatusCode::CREATED, Extension(CommitCreated(commit_oid)), Json(created)).into_response())
     }
 
-    fn attach_oid(mut res: Response, oid: git2::Oid) -> Response {
+    fn etag_headers(oid: git2::Oid) -> [(header::HeaderName, String); 2] {
         // ETag values should be quoted
-        let etag_value = format!("\"{}\"", oid);
-        res.headers_mut().insert(
-            header::ETAG,
-            HeaderValue::from_str(&etag_value).unwrap(),
-        );
-        res.headers_mut().insert(
-            header::ACCESS_CONTROL_EXPOSE_HEADERS,
-            HeaderValue::from_str("ETag").unwrap(),
-        );
-        res
+        [
+            (header::ETAG, format!("\"{}\"", oid)),
+            (header::ACCESS_CONTROL_EXPOSE_HEADERS, "ETag".to_owned()),
+        ]
+    }
+
+    fn attach_oid(res: Response, oid: git2::Oid) -> Response {
+        (etag_headers(oid), res).into_response()
+    }
+
+    /// `304 Not Modified` if the `If-None-Match` header names `oid`.
+    fn check_if_none_match(headers: &HeaderMap, oid: git2::Oid) -> Option<Response> {
+        let inm = headers.get(header::IF_NONE_MATCH)?;
+        if inm.to_str().unwrap_or("") != format!("\"{}\"", oid) {
+            return None;
+        }
+        Some((StatusCode::NOT_MODIFIED, etag_headers(oid)).into_response())
     }
 
     async fn make_files_path_response(
         path: String,
+        rev: Option<String>,
         state: AppState,
+        principal: Principal,
         headers: HeaderMap,
-    ) -> Response {
-        if let Some((oid, content)) = find_entry_blob(&state, &path).await {
-            // Check If-None-Match header, and shortcut to 304
-            let etag_value = format!("\"{}\"", oid);
-            if let Some(inm) = headers.get(header::IF_NONE_MATCH) {
-                if inm.to_str().unwrap_or("") == etag_value {
-                    return Response::builder()
-                        .status(StatusCode::NOT_MODIFIED)
-                        .header(header::ETAG, etag_value.clone())
-                        .header(header::ACCESS_CONTROL_EXPOSE_HEADERS, "ETag")
-                        .body(Body::empty())
-                        .unwrap();
-                }
-            }
+    ) -> Result<Response, AppError> {
+        let commit_id = match &rev {
+            Some(rev) => Some(resolve_revision(&state.repo.lock().unwrap(), rev)?),
+            None => None,
+        };
+        let (oid, content) = find_entry_blob_at(&state, &path, &principal.acl, commit_id).await.ok_or(AppError::NotFound)?;
 
-            let res = match mime_guess::from_path::<&Path>(path.as_ref()).first() {
-                Some(mime) if mime.type_() == "image" => {
-                    serve_image_content(content, path.as_ref()).await
-                },
-                _ => content_response(content, path.as_ref()),
-            };
-            attach_oid(res, oid)
-        }
-        else {
-            StatusCode::NOT_FOUND.into_response()
+        // Content at a commit named by its ID never changes, unlike at a branch, a tag or a date
+        let immutable = rev.is_some_and(|rev| rev == oid.to_string());
+        let cache_control = (header::CACHE_CONTROL, if immutable { "private, max-age=31536000, immutable" } else { "no-cache" });
+
+        // Check If-None-Match header, and shortcut to 304
+        if let Some(not_modified) = check_if_none_match(&headers, oid) {
+            return Ok(([cache_control], not_modified).into_response());
         }
+
+        let res = match mime_guess::from_path::<&Path>(path.as_ref()).first() {
+            Some(mime) if mime.type_() == "image" => {
+                serve_image_content(content, path.as_ref()).await?
+            },
+            _ => content_response(content, path.as_ref()),
+        };
+        Ok(([cache_control], attach_oid(res, oid)).into_response())
     }
 
     fn head_from_full(full: Response) -> Response {
@@ -1428,21 +2517,25 @@ Important:
     }
 
     pub async fn get_files_path(
-        extract::Path(path): extract::Path<String>,
+        AppPath(path): AppPath<String>,
+        AppQuery(query): AppQuery<FilesQuery>,
         extract::State(state): extract::State<AppState>,
+        extract::Extension(principal): extract::Extension<Principal>,
         headers: HeaderMap,
-    ) -> Response {
+    ) -> Result<Response, AppError> {
         tracing::debug!("v2::get_files_path");
-        make_files_path_response(path, state, headers).await
+        make_files_path_response(path, query.rev, state, principal, headers).await
     }
 
     pub async fn head_files_path(
-        extract::Path(path): extract::Path<String>,
+        AppPath(path): AppPath<String>,
+        AppQuery(query): AppQuery<FilesQuery>,
         extract::State(state): extract::State<AppState>,
+        extract::Extension(principal): extract::Extension<Principal>,
         headers: HeaderMap,
     ) -> Response {
         tracing::debug!("v2::head_files_path");
-        head_from_full(make_files_path_response(path, state, headers).await)
+        head_from_full(make_files_path_response(path, query.rev, state, principal, headers).await.into_response())
     }
 
     #[derive(Deserialize)]
@@ -1451,94 +2544,435 @@ Important:
     }
 
     pub async fn get_tasks(
-        extract::Query(query): extract::Query<TaskQuery>,
+        AppQuery(query): AppQuery<TaskQuery>,
         extract::State(state): extract::State<AppState>,
+        extract::Extension(principal): extract::Extension<Principal>,
         headers: HeaderMap,
-    ) -> Response {
+    ) -> Result<Response, AppError> {
         tracing::debug!("v2::get_tasks");
 
         // Load task entries
-        let (head_commit_id, entries) = state.get_entries(Some(".tasks/*")).await.unwrap();
+        let (head_commit_id, entries) = state.get_entries(Some(".tasks/*"), &principal.acl).await?;
 
         // Check If-None-Match header, and shortcut to 304
-        let etag_value = format!("\"{}\"", head_commit_id);
-        if let Some(inm) = headers.get(header::IF_NONE_MATCH) {
-            if inm.to_str().unwrap_or("") == etag_value {
-                return Response::builder()
-                    .status(StatusCode::NOT_MODIFIED)
-                    .header(header::ETAG, etag_value.clone())
-                    .header(header::ACCESS_CONTROL_EXPOSE_HEADERS, "ETag")
-                    .body(Body::empty())
-                    .unwrap();
-            }
+        if let Some(not_modified) = check_if_none_match(&headers, head_commit_id) {
+            return Ok(not_modified);
         }
 
         match query.format.as_deref() {
             Some("tree") => {
                 // Tree structure response
-                let roots = entries_to_tree(&entries, Some(".tasks")).unwrap();
+                let roots = entries_to_tree(&entries, Some(".tasks"))?;
                 let response = Json(roots).into_response();
-                attach_oid(response, head_commit_id)
+                Ok(attach_oid(response, head_commit_id))
             },
             _ => {
                 // List structure response
                 let response = Json(entries).into_response();
-                attach_oid(response, head_commit_id)
+                Ok(attach_oid(response, head_commit_id))
             },
         }
     }
 
     pub async fn get_events(
         extract::State(state): extract::State<AppState>,
+        extract::Extension(principal): extract::Extension<Principal>,
         headers: HeaderMap,
-    ) -> Response {
+    ) -> Result<Response, AppError> {
         tracing::debug!("v2::get_events");
 
         // Load event entries
-        let (head_commit_id, entries) = state.get_entries(Some(".events/*")).await.unwrap();
+        let (head_commit_id, entries) = state.get_entries(Some(".events/*"), &principal.acl).await?;
 
         // Check If-None-Match header, and shortcut to 304
-        let etag_value = format!("\"{}\"", head_commit_id);
-        if let Some(inm) = headers.get(header::IF_NONE_MATCH) {
-            if inm.to_str().unwrap_or("") == etag_value {
-                return Response::builder()
-                    .status(StatusCode::NOT_MODIFIED)
-                    .header(header::ETAG, etag_value.clone())
-                    .header(header::ACCESS_CONTROL_EXPOSE_HEADERS, "ETag")
-                    .body(Body::empty())
-                    .unwrap();
-            }
+        if let Some(not_modified) = check_if_none_match(&headers, head_commit_id) {
+            return Ok(not_modified);
         }
 
         // Normal response
         let response = Json(entries).into_response();
-        attach_oid(response, head_commit_id)
+        Ok(attach_oid(response, head_commit_id))
+    }
+
+    pub async fn get_shares(
+        extract::State(state): extract::State<AppState>,
+        extract::Extension(principal): extract::Extension<Principal>,
+    ) -> Result<Json<Vec<ShareLinkInfo>>, AppError> {
+        tracing::debug!("v2::get_shares");
+        let shares = state.list_share_links(&principal.user).await?
+            .into_iter()
+            .map(|share| ShareLinkInfo { url: share_link_url(&share), share })
+            .collect();
+        Ok(Json(shares))
+    }
+
+    pub async fn post_shares(
+        extract::State(state): extract::State<AppState>,
+        extract::Extension(principal): extract::Extension<Principal>,
+        AppJson(new_share): AppJson<NewShareLink>,
+    ) -> Result<Response, AppError> {
+        tracing::debug!("v2::post_shares");
+
+        // Whole seconds, since that is what gets stored and signed
+        let now = Utc::now().with_nanosecond(0).unwrap_or_default();
+        let expires_at = new_share.expires_at
+            .and_then(|t| t.with_nanosecond(0))
+            .unwrap_or(now + Duration::days(7));
+        if expires_at <= now {
+            return Err(AppError::BadRequest("the expiry must be in the future".to_owned()));
+        }
+        if !principal.permits(&Method::GET, "/v2/files/*path", &new_share.path) {
+            return Err(AppError::Forbidden);
+        }
+
+        // Only existing paths the user can read may be shared
+        let blob_id = {
+            let repo = state.repo.lock().unwrap();
+            let head_tree = head_commit(&repo)?.ok_or(AppError::NotFound)?.tree()?;
+            let entry = head_tree.get_path(Path::new(&new_share.path)).ok();
+            match entry {
+                Some(entry) if entry.kind() == Some(git2::ObjectType::Blob) && principal.acl.can_read(&new_share.path) => entry.id(),
+                _ => return Err(AppError::NotFound),
+            }
+        };
+
+        let share = ShareLink {
+            id: random_token(),
+            path: new_share.path,
+            blob_id: new_share.pinned.then(|| blob_id.to_string()),
+            created_by: principal.user.clone(),
+            created_at: now,
+            expires_at,
+            revoked_at: None,
+        };
+        sqlx::query("INSERT INTO share_link (id, path, blob_id, created_by, created_at, expires_at) VALUES (?, ?, ?, ?, ?, ?);")
+            .bind(&share.id)
+            .bind(&share.path)
+            .bind(&share.blob_id)
+            .bind(&share.created_by)
+            .bind(share.created_at.timestamp())
+            .bind(share.expires_at.timestamp())
+            .execute(&state.cache_db_writer)
+            .await
+            .context("Failed to create a share link")?;
+        tracing::info!("User '{}' shared {} until {}", principal.user, share.path, share.expires_at);
+
+        Ok((StatusCode::CREATED, Json(ShareLinkInfo { url: share_link_url(&share), share })).into_response())
+    }
+
+    pub async fn delete_shares_id(
+        AppPath(id): AppPath<String>,
+        extract::State(state): extract::State<AppState>,
+        extract::Extension(principal): extract::Extension<Principal>,
+    ) -> Result<Response, AppError> {
+        tracing::debug!("v2::delete_shares_id");
+
+        let result = sqlx::query("UPDATE share_link SET revoked_at = ? WHERE id = ? AND created_by = ? AND revoked_at IS NULL;")
+            .bind(Utc::now().timestamp())
+            .bind(&id)
+            .bind(&principal.user)
+            .execute(&state.cache_db_writer)
+            .await
+            .context("Failed to revoke a share link")?;
+        if result.rows_affected() == 0 {
+            return Err(AppError::NotFound);
+        }
+        Ok(Json(&true).into_response())
+    }
+
+    pub async fn post_restore_path(
+        AppPath(path): AppPath<RepoPath>,
+        extract::State(state): extract::State<AppState>,
+        extract::Extension(principal): extract::Extension<Principal>,
+        headers: HeaderMap,
+        AppJson(restore): AppJson<RevisionRestore>,
+    ) -> Result<Response, AppError> {
+        tracing::debug!("v2::post_restore_path");
+
+        if !principal.acl.can_read(&path) {
+            return Err(AppError::NotFound);
+        }
+        if !principal.acl.can_write(&path) {
+            return Err(AppError::Forbidden);
+        }
+
+        let commit_oid = {
+            let repo = state.repo.lock().unwrap();
+
+            let commit = repo.find_commit(resolve_revision(&repo, &restore.rev)?)?;
+            let entry = commit.tree()?.get_path(path.as_ref())
+                .ok()
+                .filter(|entry| entry.kind() == Some(git2::ObjectType::Blob))
+                .ok_or(AppError::NotFound)?;
+
+            let head_commit = head_commit(&repo)?;
+            let mut index = index_of(head_commit.as_ref())?;
+            check_if_match(&repo, &headers, &path)?;
+            if index.get_path(path.as_ref(), 0).is_some_and(|current| current.id == entry.id() && current.mode == entry.filemode() as u32) {
+                return Err(AppError::conflict("the path is already at that version", ()));
+            }
+
+            // A directory created at the path since is not replaced
+            let existing: Vec<String> = index_paths_under(&index, &path).into_iter().filter(|p| p != &*path).collect();
+            if !existing.is_empty() {
+                return Err(AppError::conflict("a directory exists at the path", ConflictingPaths { paths: existing }));
+            }
+
+            let mut index_entry = blob_index_entry(&path, entry.id());
+            index_entry.mode = entry.filemode() as u32;
+            index.add(&index_entry)?;
+
+            let tree_oid = index.write_tree_to(&repo)?;
+            let tree = repo.find_tree(tree_oid)?;
+
+            let author = principal.signature()?;
+            let message = format!("Restore {} to {}", &*path, &commit.id().to_string()[..7]);
+            let parents: Vec<_> = head_commit.iter().collect();
+            commit_to_head(&repo, state.signer.as_deref(), &author, &message, &tree, &parents)?
+        };
+        tracing::info!("User '{}' restored {} as {}", principal.user, &*path, commit_oid);
+
+        let created = CreatedCommit {
+            commit_id: commit_oid.to_string(),
+            paths: vec![path.to_string()],
+        };
+        Ok((Extension(CommitCreated(commit_oid)), Json(created)).into_response())
+    }
+
+    pub async fn post_revert(
+        extract::State(state): extract::State<AppState>,
+        extract::Extension(principal): extract::Extension<Principal>,
+        AppJson(revert): AppJson<CommitRevert>,
+    ) -> Result<Response, AppError> {
+        tracing::debug!("v2::post_revert");
+
+        let (reverted_oid, changed) = {
+            let repo = state.repo.lock().unwrap();
+            let commit = repo.find_commit(resolve_revision(&repo, &revert.commit_id)?)?;
+            if commit.parent_count() != 1 {
+                return Err(AppError::BadRequest("only commits with a single parent can be reverted".to_owned()));
+            }
+            // Only commits made through the server, not those pushed to the repository
+            if !is_server_commit(&repo, &commit)? {
+                return Err(AppError::BadRequest("the commit was not made by the server".to_owned()));
+            }
+            let diff = repo.diff_tree_to_tree(Some(&commit.parent(0)?.tree()?), Some(&commit.tree()?), None)?;
+            let changed: Vec<String> = diff.deltas()
+                .flat_map(|delta| [delta.old_file().path(), delta.new_file().path()])
+                .flatten()
+                .map(|path| path.to_string_lossy().into_owned())
+                .collect::<BTreeSet<_>>()
+                .into_iter()
+                .collect();
+            (commit.id(), changed)
+        };
+
+        if !changed.iter().all(|path| principal.permits(&Method::POST, "/v2/revert", path) && principal.acl.can_write(path)) {
+            return Err(AppError::Forbidden);
+        }
+
+        let (commit_oid, deleted) = {
+            let repo = state.repo.lock().unwrap();
+
+            let commit = repo.find_commit(reverted_oid)?;
+            let head_commit = head_commit(&repo)?.ok_or(AppError::NotFound)?;
+            let head_tree = head_commit.tree()?;
+
+            // Later changes to the same lines make the revert conflict
+            let mut index = repo.revert_commit(&commit, &head_commit, 0, None)?;
+            if index.has_conflicts() {
+                let paths: BTreeSet<String> = index.conflicts()?
+                    .filter_map(|conflict| conflict.ok())
+                    .filter_map(|conflict| conflict.our.or(conflict.their).or(conflict.ancestor))
+                    .map(|entry| String::from_utf8_lossy(&entry.path).into_owned())
+                    .collect();
+                return Err(AppError::conflict("later commits changed the same paths", ConflictingPaths { paths: paths.into_iter().collect() }));
+            }
+
+            let tree_oid = index.write_tree_to(&repo)?;
+            if tree_oid == head_tree.id() {
+                return Err(AppError::conflict("the changes of the commit are already undone", ()));
+            }
+            let tree = repo.find_tree(tree_oid)?;
+
+            let deleted = repo.diff_tree_to_tree(Some(&head_tree), Some(&tree), None)?
+                .deltas()
+                .filter(|delta| delta.status() == git2::Delta::Deleted)
+                .filter_map(|delta| Some(trash::DeletedPath {
+                    path: delta.old_file().path()?.to_string_lossy().into_owned(),
+                    blob_id: delta.old_file().id(),
+                    mode: u32::from(delta.old_file().mode()),
+                }))
+                .collect::<Vec<_>>();
+
+            let author = principal.signature()?;
+            let summary = String::from_utf8_lossy(commit.summary_bytes().unwrap_or_default()).into_owned();
+            let message = format!("Revert \"{}\"\n\nThis reverts commit {}.\n", summary, commit.id());
+            let commit_oid = commit_to_head(&repo, state.signer.as_deref(), &author, &message, &tree, &[&head_commit])?;
+            (commit_oid, deleted)
+        };
+        tracing::info!("User '{}' reverted {} as {}", principal.user, reverted_oid, commit_oid);
+
+        if let Err(e) = state.record_trash(commit_oid, &principal.user, &deleted).await {
+            tracing::error!("failed to record deleted paths in the trash: {:?}", e);
+        }
+
+        let created = CreatedCommit {
+            commit_id: commit_oid.to_string(),
+            paths: changed,
+        };
+        Ok((Extension(CommitCreated(commit_oid)), Json(created)).into_response())
+    }
+
+    pub async fn get_admin_users(
+        extract::State(state): extract::State<AppState>,
+    ) -> Result<Json<Vec<User>>, AppError> {
+        tracing::debug!("v2::get_admin_users");
+        Ok(Json(state.list_users().await?))
+    }
+
+    pub async fn post_admin_users(
+        extract::State(state): extract::State<AppState>,
+        AppJson(new_user): AppJson<NewUser>,
+    ) -> Result<Response, AppError> {
+        tracing::debug!("v2::post_admin_users");
+
+        if new_user.name.is_empty() || new_user.password.is_empty() {
+            return Err(AppError::BadRequest("a name and a password are required".to_owned()));
+        }
+        if state.find_user(&new_user.name).await?.is_some() {
+            return Err(AppError::conflict(format!("user '{}' already exists", new_user.name), ()));
+        }
+
+        let hash = hash_password(&new_user.password)?;
+        sqlx::query("INSERT INTO user (name, email, display_name, hash, admin, created_at) VALUES (?, ?, ?, ?, ?, ?);")
+            .bind(&new_user.name)
+            .bind(&new_user.email)
+            .bind(&new_user.display_name)
+            .bind(hash)
+            .bind(new_user.admin)
+            .bind(Utc::now().timestamp())
+            .execute(&state.cache_db_writer)
+            .await
+            .context("Failed to create a user")?;
+        tracing::info!("Created user '{}'", new_user.name);
+
+        let user = state.find_user(&new_user.name).await?
+            .context("Created user should exist")?;
+        Ok((StatusCode::CREATED, Json(user)).into_response())
+    }
+
+    async fn set_user_disabled(
+        state: &AppState,
+        name: &str,
+        disabled: bool,
+    ) -> Result<Response, AppError> {
+        let result = sqlx::query("UPDATE user SET disabled = ? WHERE name = ?;")
+            .bind(disabled)
+            .bind(name)
+            .execute(&state.cache_db_writer)
+            .await
+            .context("Failed to update a user")?;
+        if result.rows_affected() == 0 {
+            return Err(AppError::NotFound);
+        }
+        tracing::info!("{} user '{}'", if disabled { "Disabled" } else { "Enabled" }, name);
+        Ok(Json(&true).into_response())
+    }
+
+    pub async fn post_admin_users_disable(
+        AppPath(name): AppPath<String>,
+        extract::State(state): extract::State<AppState>,
+    ) -> Result<Response, AppError> {
+        tracing::debug!("v2::post_admin_users_disable");
+        set_user_disabled(&state, &name, true).await
+    }
+
+    pub async fn post_admin_users_enable(
+        AppPath(name): AppPath<String>,
+        extract::State(state): extract::State<AppState>,
+    ) -> Result<Response, AppError> {
+        tracing::debug!("v2::post_admin_users_enable");
+        set_user_disabled(&state, &name, false).await
+    }
+
+    pub async fn put_admin_users_password(
+        AppPath(name): AppPath<String>,
+        extract::State(state): extract::State<AppState>,
+        AppJson(reset): AppJson<PasswordReset>,
+    ) -> Result<Response, AppError> {
+        tracing::debug!("v2::put_admin_users_password");
+
+        if reset.password.is_empty() {
+            return Err(AppError::BadRequest("a password is required".to_owned()));
+        }
+
+        let hash = hash_password(&reset.password)?;
+        let result = sqlx::query("UPDATE user SET hash = ? WHERE name = ?;")
+            .bind(hash)
+            .bind(&name)
+            .execute(&state.cache_db_writer)
+            .await
+            .context("Failed to update a user")?;
+        if result.rows_affected() == 0 {
+            return Err(AppError::NotFound);
+        }
+        // Existing sessions were authenticated with the old password
+        sqlx::query("UPDATE session SET revoked_at = ? WHERE user = ? AND revoked_at IS NULL;")
+            .bind(Utc::now().timestamp())
+            .bind(&name)
+            .execute(&state.cache_db_writer)
+            .await
+            .context("Failed to revoke sessions")?;
+        tracing::info!("Reset password of user '{}'", name);
+        Ok(Json(&true).into_response())
+    }
+
+    pub async fn get_admin_audit(
+        extract::State(state): extract::State<AppState>,
+        AppQuery(query): AppQuery<AuditQuery>,
+    ) -> Result<Json<Vec<AuditEvent>>, AppError> {
+        tracing::debug!("v2::get_admin_audit");
+        Ok(Json(state.query_audit_log(&query).await?))
     }
+
 }
 
 mod models {
     use std::borrow::Cow;
     use std::collections::HashMap;
+    use std::env;
+    use std::fmt;
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+    use std::time::{self, Instant};
     use std::path::{Component, Path, PathBuf};
     use std::sync::{Arc, Mutex};
     use std::option::Option;
 
     use anyhow::{bail, ensure, Context, Result};
     use axum::{
-        extract,
-        http::StatusCode,
+        extract::{
+            self,
+            multipart::{MultipartError, MultipartRejection},
+            rejection::{JsonRejection, PathRejection, QueryRejection},
+        },
+        http::{header, StatusCode},
         response::{IntoResponse, Response},
+        Json,
     };
-    use chrono::{DateTime, FixedOffset, offset::TimeZone};
+    use chrono::{DateTime, FixedOffset, Utc, offset::TimeZone};
     use git2::{Repository, Oid};
     use serde::{Deserialize, Serialize};
-    use serde_yaml;
     use sqlx::{Row, SqlitePool, sqlite::SqliteRow};
     use tokio::{
         sync::watch,
     };
     use uuid::Uuid;
 
+    use super::acl::PathAccess;
+    use super::auth::LoginThrottle;
+
     pub type Metadata = serde_yaml::Value;
 
     #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -1714,11 +3148,12 @@ mod models {
         }
     }
 
-    #[derive(Debug, Serialize, Deserialize)]
+    #[derive(Debug, Serialize, Deserialize, Clone)]
     pub struct Claims {
         pub sub: String,
         pub exp: usize,
         pub email: String,
+        pub jti: String,
     }
 
     #[derive(Debug, Clone)]
@@ -1729,38 +3164,416 @@ mod models {
         Empty(Oid),
     }
 
-    pub struct AppError(anyhow::Error);
+    /// Error of a request, responded with an `ErrorBody`.
+    #[derive(Debug)]
+    pub enum AppError {
+        /// The request is malformed.
+        BadRequest(String),
+        /// The request lacks valid credentials.
+        Unauthorized,
+        /// The password was right, but a one-time password is needed as well.
+        OtpRequired,
+        Forbidden,
+        NotFound,
+        /// The request conflicts with the current state, which `details` describe.
+        Conflict {
+            message: String,
+            details: serde_json::Value,
+        },
+        /// The resource existed, but is no longer available.
+        Gone,
+        TooManyRequests {
+            retry_after: time::Duration,
+        },
+        /// A service the request depends on failed.
+        Upstream(anyhow::Error),
+        Internal(anyhow::Error),
+    }
 
-    impl IntoResponse for AppError {
-        fn into_response(self) -> Response {
-            tracing::error!("AppError: {:?}", self.0);
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("AppError: {}", self.0),
-            )
-                .into_response()
-        }
+    /// Body of error responses.
+    ///
+    /// `code` is stable for clients to act on, while `message` is for humans.
+    #[derive(Debug, Serialize)]
+    pub struct ErrorBody {
+        pub code: &'static str,
+        pub message: String,
+        /// Further fields specific to the error.
+        #[serde(flatten)]
+        pub details: serde_json::Map<String, serde_json::Value>,
     }
 
-    impl<E> From<E> for AppError
-    where
-        E: Into<anyhow::Error>,
-    {
-        fn from(err: E) -> Self {
-            Self(err.into())
+    impl AppError {
+        pub fn conflict(message: impl Into<String>, details: impl Serialize) -> Self {
+            AppError::Conflict {
+                message: message.into(),
+                details: serde_json::to_value(details).unwrap_or_default(),
+            }
         }
     }
 
-    #[derive(Clone, extract::FromRef)]
-    pub struct AppState {
-        pub repo: Arc<Mutex<Repository>>,
-        pub cache_db: SqlitePool,
-        pub tx: watch::Sender<CacheState>,
-        pub http_client: reqwest::Client,
+    impl IntoResponse for AppError {
+        fn into_response(self) -> Response {
+            let mut details = serde_json::Map::new();
+            let (status, code, message) = match self {
+                AppError::BadRequest(message) => (StatusCode::BAD_REQUEST, "bad-request", message),
+                AppError::Unauthorized => (StatusCode::UNAUTHORIZED, "unauthorized", "authentication is required".to_owned()),
+                AppError::OtpRequired => {
+                    details.insert("otp_required".to_owned(), true.into());
+                    (StatusCode::UNAUTHORIZED, "otp-required", "a one-time password is required".to_owned())
+                },
+                AppError::Forbidden => (StatusCode::FORBIDDEN, "forbidden", "access is denied".to_owned()),
+                AppError::NotFound => (StatusCode::NOT_FOUND, "not-found", "not found".to_owned()),
+                AppError::Conflict { message, details: conflict_details } => {
+                    if let serde_json::Value::Object(conflict_details) = conflict_details {
+                        details = conflict_details;
+                    }
+                    (StatusCode::CONFLICT, "conflict", message)
+                },
+                AppError::Gone => (StatusCode::GONE, "gone", "no longer available".to_owned()),
+                AppError::TooManyRequests { retry_after } => {
+                    // Round up so that retrying after the given seconds is not too early
+                    let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+                    let body = ErrorBody {
+                        code: "too-many-requests",
+                        message: format!("retry after {} seconds", seconds),
+                        details,
+                    };
+                    return (
+                        StatusCode::TOO_MANY_REQUESTS,
+                        [(header::RETRY_AFTER, seconds.to_string())],
+                        Json(body),
+                    ).into_response();
+                },
+                AppError::Upstream(e) => {
+                    tracing::error!("upstream error: {:?}", e);
+                    (StatusCode::BAD_GATEWAY, "upstream", e.to_string())
+                },
+                AppError::Internal(e) => {
+                    tracing::error!("internal error: {:?}", e);
+                    (StatusCode::INTERNAL_SERVER_ERROR, "internal", "internal server error".to_owned())
+                },
+            };
+            (status, Json(ErrorBody { code, message, details })).into_response()
+        }
+    }
+
+    impl From<anyhow::Error> for AppError {
+        fn from(e: anyhow::Error) -> Self {
+            AppError::Internal(e)
+        }
+    }
+
+    impl From<git2::Error> for AppError {
+        fn from(e: git2::Error) -> Self {
+            AppError::Internal(e.into())
+        }
+    }
+
+    impl From<sqlx::Error> for AppError {
+        fn from(e: sqlx::Error) -> Self {
+            AppError::Internal(e.into())
+        }
+    }
+
+    impl From<std::io::Error> for AppError {
+        fn from(e: std::io::Error) -> Self {
+            AppError::Internal(e.into())
+        }
+    }
+
+    impl From<serde_json::Error> for AppError {
+        fn from(e: serde_json::Error) -> Self {
+            AppError::Internal(e.into())
+        }
+    }
+
+    impl From<reqwest::Error> for AppError {
+        fn from(e: reqwest::Error) -> Self {
+            AppError::Upstream(e.into())
+        }
+    }
+
+    impl From<InvalidRepoPath> for AppError {
+        fn from(e: InvalidRepoPath) -> Self {
+            AppError::BadRequest(e.to_string())
+        }
+    }
+
+    impl From<JsonRejection> for AppError {
+        fn from(rejection: JsonRejection) -> Self {
+            AppError::BadRequest(rejection.body_text())
+        }
+    }
+
+    impl From<PathRejection> for AppError {
+        fn from(rejection: PathRejection) -> Self {
+            AppError::BadRequest(rejection.body_text())
+        }
+    }
+
+    impl From<QueryRejection> for AppError {
+        fn from(rejection: QueryRejection) -> Self {
+            AppError::BadRequest(rejection.body_text())
+        }
+    }
+
+    impl From<MultipartRejection> for AppError {
+        fn from(rejection: MultipartRejection) -> Self {
+            AppError::BadRequest(rejection.body_text())
+        }
+    }
+
+    impl From<MultipartError> for AppError {
+        fn from(e: MultipartError) -> Self {
+            AppError::BadRequest(e.body_text())
+        }
+    }
+
+    /// `Json` extractor rejecting with an `AppError`.
+    #[derive(extract::FromRequest)]
+    #[from_request(via(Json), rejection(AppError))]
+    pub struct AppJson<T>(pub T);
+
+    /// `extract::Path` extractor rejecting with an `AppError`.
+    #[derive(extract::FromRequestParts)]
+    #[from_request(via(extract::Path), rejection(AppError))]
+    pub struct AppPath<T>(pub T);
+
+    /// `extract::Query` extractor rejecting with an `AppError`.
+    #[derive(extract::FromRequestParts)]
+    #[from_request(via(extract::Query), rejection(AppError))]
+    pub struct AppQuery<T>(pub T);
+
+    struct Autosave {
+        commit_id: Oid,
+        user: String,
+        path: String,
+        started_at: Instant,
+    }
+
+    /// Coalescing of successive saves of a note into a single commit.
+    ///
+    /// A save amends the commit of the previous save if it is of the same
+    /// note by the same user within `window` of the first save in the commit.
+    pub struct AutosaveCoalescing {
+        window: Option<time::Duration>,
+        last: Option<Autosave>,
+    }
+
+    impl AutosaveCoalescing {
+        pub fn from_env() -> Self {
+            let window = env::var("MORIED_AUTOSAVE_COALESCE_MINUTES").ok().map(|v| {
+                v.parse::<u64>().expect("Coalescing window in minutes represented as integer value is expected")
+            });
+            AutosaveCoalescing {
+                window: window.filter(|&minutes| minutes > 0).map(|minutes| time::Duration::from_secs(minutes * 60)),
+                last: None,
+            }
+        }
+
+        /// Whether a save may amend `head_commit_id`, which must also be checked not to be pushed.
+        pub fn can_amend(&self, head_commit_id: Oid, user: &str, path: &str, now: Instant) -> bool {
+            match (self.window, &self.last) {
+                (Some(window), Some(last)) => {
+                    last.commit_id == head_commit_id && last.user == user && last.path == path && now - last.started_at < window
+                },
+                _ => false,
+            }
+        }
+
+        pub fn record(&mut self, commit_id: Oid, user: &str, path: &str, now: Instant, amended: bool) {
+            let started_at = match &self.last {
+                Some(last) if amended => last.started_at,
+                _ => now,
+            };
+            self.last = Some(Autosave {
+                commit_id,
+                user: user.to_owned(),
+                path: path.to_owned(),
+                started_at,
+            });
+        }
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum SigningFormat {
+        Ssh,
+        OpenPgp,
+    }
+
+    impl fmt::Display for SigningFormat {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                SigningFormat::Ssh => write!(f, "SSH"),
+                SigningFormat::OpenPgp => write!(f, "OpenPGP"),
+            }
+        }
+    }
+
+    /// Signing of commits created by the server, like `git commit -S` does.
+    #[derive(Debug, Clone)]
+    pub struct CommitSigner {
+        pub format: SigningFormat,
+        /// The private key file for SSH, or the key ID for OpenPGP.
+        pub key: String,
+    }
+
+    impl CommitSigner {
+        /// `None` unless `MORIED_SIGNING_KEY` is set.
+        pub fn from_env() -> Option<Self> {
+            let key = env::var("MORIED_SIGNING_KEY").ok().filter(|key| !key.is_empty())?;
+            let format = match env::var("MORIED_SIGNING_FORMAT").as_deref() {
+                Ok("ssh") => SigningFormat::Ssh,
+                Ok("openpgp") | Err(_) => SigningFormat::OpenPgp,
+                Ok(format) => panic!("MORIED_SIGNING_FORMAT must be 'ssh' or 'openpgp', not '{}'", format),
+            };
+            Some(CommitSigner { format, key })
+        }
+
+        /// Check that the key can be used, so that a missing key is noticed before the first commit.
+        pub fn check(&self) -> Result<()> {
+            match self.format {
+                SigningFormat::Ssh => {
+                    ensure!(Path::new(&self.key).is_file(), "SSH key file {} does not exist", self.key);
+                    Ok(())
+                },
+                SigningFormat::OpenPgp => {
+                    let output = Command::new("gpg")
+                        .args(["--batch", "--list-secret-keys", &self.key])
+                        .output()
+                        .context("Failed to execute gpg")?;
+                    ensure!(output.status.success(), "no OpenPGP secret key {}: {}", self.key, String::from_utf8_lossy(&output.stderr).trim());
+                    Ok(())
+                },
+            }
+        }
+
+        /// Armored detached signature of a commit buffer.
+        pub fn sign(&self, data: &[u8]) -> Result<String> {
+            let mut command = match self.format {
+                SigningFormat::Ssh => {
+                    let mut command = Command::new("ssh-keygen");
+                    command.args(["-Y", "sign", "-n", "git", "-f", &self.key]);
+                    command
+                },
+                SigningFormat::OpenPgp => {
+                    let mut command = Command::new("gpg");
+                    command.args(["--batch", "--armor", "--detach-sign", "--local-user", &self.key]);
+                    command
+                },
+            };
+            let mut child = command
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+                .with_context(|| format!("Failed to execute {:?}", command.get_program()))?;
+            child.stdin.take().context("stdin should be piped")?.write_all(data)?;
+            let output = child.wait_with_output()?;
+            if !output.status.success() {
+                bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+            }
+            String::from_utf8(output.stdout).context("Signature should be ASCII-armored")
+        }
+    }
+
+    #[derive(Clone, extract::FromRef)]
+    pub struct AppState {
+        pub repo: Arc<Mutex<Repository>>,
+        pub cache_db: SqlitePool,
+        #[from_ref(skip)]
+        pub cache_db_writer: SqlitePool,
+        pub tx: watch::Sender<CacheState>,
+        pub login_throttle: Arc<Mutex<LoginThrottle>>,
+        pub autosave: Arc<Mutex<AutosaveCoalescing>>,
+        /// Signer of commits, if they are to be signed.
+        pub signer: Option<Arc<CommitSigner>>,
+        pub http_client: reqwest::Client,
     }
 
     impl AppState {
-        pub async fn get_entries(&self, pattern_opt: Option<&str>) -> Result<(Oid, Vec<ListEntry>)> {
+        pub async fn find_user(&self, name: &str) -> Result<Option<User>> {
+            let user = sqlx::query("SELECT * FROM user WHERE name = ?;")
+                .bind(name)
+                .map(User::from_row)
+                .fetch_optional(&self.cache_db)
+                .await?;
+            Ok(user)
+        }
+
+        pub async fn find_user_by_oidc_identity(&self, issuer: &str, subject: &str) -> Result<Option<User>> {
+            let user = sqlx::query("SELECT * FROM user WHERE oidc_issuer = ? AND oidc_subject = ?;")
+                .bind(issuer)
+                .bind(subject)
+                .map(User::from_row)
+                .fetch_optional(&self.cache_db)
+                .await?;
+            Ok(user)
+        }
+
+        pub async fn find_users_by_email(&self, email: &str) -> Result<Vec<User>> {
+            let users = sqlx::query("SELECT * FROM user WHERE email = ? COLLATE NOCASE;")
+                .bind(email)
+                .map(User::from_row)
+                .fetch_all(&self.cache_db)
+                .await?;
+            Ok(users)
+        }
+
+        pub async fn find_share_link(&self, id: &str) -> Result<Option<ShareLink>> {
+            let share = sqlx::query("SELECT * FROM share_link WHERE id = ?;")
+                .bind(id)
+                .map(ShareLink::from_row)
+                .fetch_optional(&self.cache_db)
+                .await?;
+            Ok(share)
+        }
+
+        pub async fn list_share_links(&self, user: &str) -> Result<Vec<ShareLink>> {
+            let shares = sqlx::query("SELECT * FROM share_link WHERE created_by = ? ORDER BY created_at;")
+                .bind(user)
+                .map(ShareLink::from_row)
+                .fetch_all(&self.cache_db)
+                .await?;
+            Ok(shares)
+        }
+
+        /// Newest events first.
+        pub async fn query_audit_log(&self, query: &AuditQuery) -> Result<Vec<AuditEvent>> {
+            let events = sqlx::query("
+                    SELECT * FROM audit_log
+                    WHERE (?1 IS NULL OR actor = ?1)
+                        AND (?2 IS NULL OR route = ?2)
+                        AND (?3 IS NULL OR path = ?3 OR substr(path, 1, length(?3) + 1) = ?3 || '/')
+                        AND (?4 IS NULL OR status = ?4)
+                        AND (?5 IS NULL OR time >= ?5)
+                        AND (?6 IS NULL OR time < ?6)
+                    ORDER BY id DESC
+                    LIMIT ?7 OFFSET ?8;
+                ")
+                .bind(&query.actor)
+                .bind(&query.route)
+                .bind(query.path.as_ref().map(|path| path.trim_end_matches('/')))
+                .bind(query.status)
+                .bind(query.since.map(|t| t.timestamp()))
+                .bind(query.until.map(|t| t.timestamp()))
+                .bind(query.limit.unwrap_or(100).min(1000))
+                .bind(query.offset.unwrap_or(0))
+                .map(AuditEvent::from_row)
+                .fetch_all(&self.cache_db)
+                .await?;
+            Ok(events)
+        }
+
+        pub async fn list_users(&self) -> Result<Vec<User>> {
+            let users = sqlx::query("SELECT * FROM user ORDER BY name;")
+                .map(User::from_row)
+                .fetch_all(&self.cache_db)
+                .await?;
+            Ok(users)
+        }
+
+        pub async fn get_entries(&self, pattern_opt: Option<&str>, access: &PathAccess) -> Result<(Oid, Vec<ListEntry>)> {
             let cache_state = self.check_cache_state().await?;
             let _ = self.tx.send(cache_state.clone());
             let cache_commit_id = match cache_state {
@@ -1802,19 +3615,28 @@ mod models {
                         mime_type: row.get("mime_type"),
                         metadata: serde_json::from_str(&row.get::<String, _>("metadata")).unwrap(),
                         title: row.get("title"),
-                        time: time,
+                        time,
                     }
                 })
                 .fetch_all(&self.cache_db)
                 .await?;
 
+            // Hide entries the user cannot read
+            let entries = entries.into_iter()
+                .filter(|entry| entry.path.to_str().is_some_and(|path| access.can_read(path)))
+                .collect();
+
             Ok((cache_commit_id, entries))
         }
 
         pub async fn check_cache_state(
             &self,
         ) -> Result<CacheState> {
-            let head_commit_id = self.repo.lock().unwrap().head()?.peel_to_commit()?.id();
+            let head_commit_id = super::head_commit(&self.repo.lock().unwrap())?.map(|commit| commit.id());
+            let Some(head_commit_id) = head_commit_id else {
+                // Nothing to cache before the first commit
+                return Ok(CacheState::Fresh(Oid::zero()));
+            };
 
             let cache_commit_id_opt = sqlx::query(
                     "SELECT value FROM cache_state WHERE key = 'commit_id';",
@@ -1829,11 +3651,14 @@ mod models {
                 Some(cache_commit_id) if cache_commit_id == head_commit_id => {
                     Ok(CacheState::Fresh(head_commit_id))
                 },
-                Some(cache_commit_id) if super::is_ancestor(&*self.repo.lock().unwrap(), cache_commit_id, head_commit_id)? => {
-                    Ok(CacheState::Stale { cache_commit_id: cache_commit_id, head_commit_id: head_commit_id })
+                Some(cache_commit_id) if super::is_ancestor(&self.repo.lock().unwrap(), cache_commit_id, head_commit_id)? => {
+                    Ok(CacheState::Stale { cache_commit_id, head_commit_id })
+                },
+                Some(cache_commit_id) if super::amends(&self.repo.lock().unwrap(), cache_commit_id, head_commit_id)? => {
+                    Ok(CacheState::Stale { cache_commit_id, head_commit_id })
                 },
                 Some(cache_commit_id) => {
-                    Ok(CacheState::Diverged { cache_commit_id: cache_commit_id, head_commit_id: head_commit_id })
+                    Ok(CacheState::Diverged { cache_commit_id, head_commit_id })
                 },
                 None => {
                     Ok(CacheState::Empty(head_commit_id))
@@ -1842,23 +3667,383 @@ mod models {
         }
     }
 
-    #[derive(Debug, Deserialize, Serialize, Clone)]
-    pub struct Login {
+    #[derive(Debug, Serialize, Clone)]
+    pub struct User {
+        pub name: String,
+        pub email: String,
+        pub display_name: Option<String>,
+        #[serde(skip_serializing)]
+        pub hash: String,
+        pub admin: bool,
+        pub disabled: bool,
+        pub created_at: DateTime<Utc>,
+        pub totp_enabled: bool,
+        #[serde(skip_serializing)]
+        pub totp_secret: Option<String>,
+    }
+
+    impl User {
+        fn from_row(row: SqliteRow) -> Self {
+            User {
+                name: row.get("name"),
+                email: row.get("email"),
+                display_name: row.get("display_name"),
+                hash: row.get("hash"),
+                admin: row.get("admin"),
+                disabled: row.get("disabled"),
+                created_at: Utc.timestamp_opt(row.get("created_at"), 0).unwrap(),
+                totp_enabled: row.get("totp_enabled"),
+                totp_secret: row.get("totp_secret"),
+            }
+        }
+    }
+
+    /// Response extension telling the audit log who made a request.
+    #[derive(Debug, Clone)]
+    pub struct AuditActor {
         pub user: String,
+        pub token_id: Option<String>,
+    }
+
+    /// Response extension telling the audit log which commit a request created.
+    #[derive(Debug, Clone)]
+    pub struct CommitCreated(pub Oid);
+
+    #[derive(Debug, Serialize, Clone)]
+    pub struct AuditEvent {
+        pub id: i64,
+        pub time: DateTime<Utc>,
+        pub actor: Option<String>,
+        pub token_id: Option<String>,
+        pub method: String,
+        pub route: String,
+        pub path: Option<String>,
+        pub status: u16,
+        pub commit_id: Option<String>,
+        pub client_ip: String,
+    }
+
+    impl AuditEvent {
+        fn from_row(row: SqliteRow) -> Self {
+            AuditEvent {
+                id: row.get("id"),
+                time: Utc.timestamp_opt(row.get("time"), 0).unwrap(),
+                actor: row.get("actor"),
+                token_id: row.get("token_id"),
+                method: row.get("method"),
+                route: row.get("route"),
+                path: row.get("path"),
+                status: row.get("status"),
+                commit_id: row.get("commit_id"),
+                client_ip: row.get("client_ip"),
+            }
+        }
+    }
+
+    #[derive(Debug, Deserialize, Clone)]
+    pub struct AuditQuery {
+        pub actor: Option<String>,
+        pub route: Option<String>,
+        /// Matches the path and everything under it.
+        pub path: Option<String>,
+        pub status: Option<u16>,
+        pub since: Option<DateTime<Utc>>,
+        pub until: Option<DateTime<Utc>>,
+        /// At most 1000, defaults to 100.
+        pub limit: Option<u32>,
+        pub offset: Option<u32>,
+    }
+
+    #[derive(Debug, Deserialize, Clone)]
+    pub struct RevisionRestore {
+        /// The revision to restore the path to.
+        pub rev: String,
+    }
+
+    #[derive(Debug, Deserialize, Clone)]
+    pub struct CommitRevert {
+        /// A commit created by the server.
+        pub commit_id: String,
+    }
+
+    #[derive(Debug, Serialize, Clone)]
+    pub struct ShareLink {
+        pub id: String,
+        pub path: String,
+        /// The blob the link is pinned to, or `None` to follow the latest version.
+        pub blob_id: Option<String>,
+        pub created_by: String,
+        pub created_at: DateTime<Utc>,
+        pub expires_at: DateTime<Utc>,
+        pub revoked_at: Option<DateTime<Utc>>,
+    }
+
+    impl ShareLink {
+        fn from_row(row: SqliteRow) -> Self {
+            ShareLink {
+                id: row.get("id"),
+                path: row.get("path"),
+                blob_id: row.get("blob_id"),
+                created_by: row.get("created_by"),
+                created_at: Utc.timestamp_opt(row.get("created_at"), 0).unwrap(),
+                expires_at: Utc.timestamp_opt(row.get("expires_at"), 0).unwrap(),
+                revoked_at: row.get::<Option<i64>, _>("revoked_at").map(|t| Utc.timestamp_opt(t, 0).unwrap()),
+            }
+        }
+    }
+
+    #[derive(Debug, Serialize, Clone)]
+    pub struct ShareLinkInfo {
+        /// Signed URL relative to `MORIED_ROOT_PATH`.
+        pub url: String,
+        #[serde(flatten)]
+        pub share: ShareLink,
+    }
+
+    #[derive(Debug, Deserialize, Clone)]
+    pub struct NewShareLink {
+        pub path: String,
+        /// Pin the link to the current version instead of following later changes.
+        #[serde(default)]
+        pub pinned: bool,
+        /// Defaults to seven days from now.
+        pub expires_at: Option<DateTime<Utc>>,
+    }
+
+    #[derive(Debug, Deserialize, Clone)]
+    pub struct ShareQuery {
+        pub exp: i64,
+        pub sig: String,
+    }
+
+    #[derive(Debug, Deserialize, Clone)]
+    pub struct NewUser {
+        pub name: String,
+        pub email: String,
+        pub display_name: Option<String>,
+        pub password: String,
+        #[serde(default)]
+        pub admin: bool,
+    }
+
+    #[derive(Debug, Deserialize, Clone)]
+    pub struct PasswordReset {
         pub password: String,
     }
 
+    #[derive(Debug, Serialize, Clone)]
+    pub struct OidcAuthorization {
+        pub authorization_url: String,
+    }
+
+    #[derive(Debug, Deserialize, Clone)]
+    pub struct OidcCallback {
+        pub code: String,
+        pub state: String,
+    }
+
     #[derive(Debug, Deserialize, Serialize, Clone)]
     pub enum NoteSave {
         Save {
             content: String,
             message: String,
+            /// Blob ID of the version the content was edited from, to merge with later changes.
+            base: Option<String>,
         },
         Rename {
             from: String,
         },
     }
 
+    /// Path of a file or directory in the repository that is safe to write to.
+    ///
+    /// It is valid UTF-8, relative, has no NUL bytes, no `.`, `..` or empty
+    /// components and does not reach into `.git`. A trailing `/` is dropped.
+    #[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+    #[serde(try_from = "String", into = "String")]
+    pub struct RepoPath(String);
+
+    impl RepoPath {
+        pub fn new(path: &str) -> Result<Self, InvalidRepoPath> {
+            if path.starts_with('/') {
+                return Err(InvalidRepoPath::Absolute(path.to_owned()));
+            }
+            let path = path.trim_end_matches('/');
+            if path.is_empty() {
+                return Err(InvalidRepoPath::Empty);
+            }
+            if path.contains('\0') {
+                return Err(InvalidRepoPath::NulByte(path.to_owned()));
+            }
+            for component in path.split('/') {
+                match component {
+                    "" => return Err(InvalidRepoPath::EmptyComponent(path.to_owned())),
+                    "." | ".." => return Err(InvalidRepoPath::Traversal(path.to_owned())),
+                    _ if component.eq_ignore_ascii_case(".git") => return Err(InvalidRepoPath::Reserved(path.to_owned())),
+                    _ => (),
+                }
+            }
+            Ok(RepoPath(path.to_owned()))
+        }
+
+        pub fn as_str(&self) -> &str {
+            &self.0
+        }
+    }
+
+    impl std::ops::Deref for RepoPath {
+        type Target = str;
+
+        fn deref(&self) -> &str {
+            &self.0
+        }
+    }
+
+    impl std::fmt::Display for RepoPath {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            self.0.fmt(f)
+        }
+    }
+
+    impl TryFrom<String> for RepoPath {
+        type Error = InvalidRepoPath;
+
+        fn try_from(path: String) -> Result<Self, Self::Error> {
+            RepoPath::new(&path)
+        }
+    }
+
+    impl From<RepoPath> for String {
+        fn from(path: RepoPath) -> Self {
+            path.0
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    pub enum InvalidRepoPath {
+        Empty,
+        Absolute(String),
+        NulByte(String),
+        EmptyComponent(String),
+        Traversal(String),
+        Reserved(String),
+    }
+
+    impl std::fmt::Display for InvalidRepoPath {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                InvalidRepoPath::Empty => write!(f, "path is empty"),
+                InvalidRepoPath::Absolute(path) => write!(f, "path must be relative: {:?}", path),
+                InvalidRepoPath::NulByte(path) => write!(f, "path contains a NUL byte: {:?}", path),
+                InvalidRepoPath::EmptyComponent(path) => write!(f, "path contains an empty component: {:?}", path),
+                InvalidRepoPath::Traversal(path) => write!(f, "path contains '.' or '..': {:?}", path),
+                InvalidRepoPath::Reserved(path) => write!(f, "path reaches into .git: {:?}", path),
+            }
+        }
+    }
+
+    /// A version of a note, such as the current one when a write conflicts with it.
+    #[derive(Debug, Serialize, Clone)]
+    pub struct NoteVersion {
+        /// `None` in a repository without commits.
+        pub commit_id: Option<String>,
+        /// `None` if the note does not exist.
+        pub blob_id: Option<String>,
+        /// `None` if the note does not exist or is not UTF-8 text, such as an image.
+        pub content: Option<String>,
+    }
+
+    #[derive(Debug, Deserialize, Clone)]
+    pub struct NewCommit {
+        pub message: String,
+        pub operations: Vec<CommitOperation>,
+    }
+
+    /// Change to a single path, applied in order by `POST /v2/commits`.
+    #[derive(Debug, Deserialize, Clone)]
+    #[serde(tag = "op", rename_all = "kebab-case")]
+    pub enum CommitOperation {
+        /// Put a file, which must not exist unless `if_match` is given.
+        Put {
+            path: RepoPath,
+            content: String,
+            /// Versions of the path at HEAD to overwrite, as in `If-Match`.
+            if_match: Option<String>,
+        },
+        /// Put a file uploaded in the multipart field named `upload`.
+        Upload {
+            path: RepoPath,
+            upload: String,
+            if_match: Option<String>,
+        },
+        /// Delete a file, or a directory with everything under it.
+        Delete {
+            path: RepoPath,
+            if_match: Option<String>,
+        },
+        /// Rename a file, or a directory with everything under it, to a path that does not exist.
+        Rename {
+            from: RepoPath,
+            to: RepoPath,
+            /// Versions of `from` at HEAD, as in `If-Match`.
+            if_match: Option<String>,
+        },
+    }
+
+    #[derive(Debug, Serialize, Clone)]
+    pub struct RenamedPath {
+        pub from: String,
+        pub to: String,
+    }
+
+    #[derive(Debug, Deserialize, Clone)]
+    pub struct FilesQuery {
+        /// A commit ID, a tag, a branch or `@{<date>}`; defaults to HEAD.
+        pub rev: Option<String>,
+    }
+
+    #[derive(Debug, Serialize, Clone)]
+    pub struct CreatedCommit {
+        pub commit_id: String,
+        pub paths: Vec<String>,
+    }
+
+    /// Details of a conflict with paths that exist.
+    #[derive(Debug, Serialize, Clone)]
+    pub struct ConflictingPaths {
+        pub paths: Vec<String>,
+    }
+
+    /// Details of a conflict with the current version of a note.
+    #[derive(Debug, Serialize, Clone)]
+    pub struct ConflictingVersion {
+        pub current: NoteVersion,
+    }
+
+    /// Details of a save that cannot be merged with the current version.
+    #[derive(Debug, Serialize, Clone)]
+    pub struct MergeConflict {
+        pub current: NoteVersion,
+        pub hunks: Vec<MergeHunk>,
+    }
+
+    /// Part of a merged note, either merged cleanly or conflicting.
+    #[derive(Debug, Serialize, Clone, PartialEq)]
+    #[serde(tag = "kind", rename_all = "kebab-case")]
+    pub enum MergeHunk {
+        Resolved {
+            text: String,
+        },
+        Conflict {
+            /// Lines of the saved content.
+            saved: String,
+            /// Lines of the version the save was based on.
+            base: String,
+            /// Lines of the current version.
+            current: String,
+        },
+    }
+
     #[derive(Debug, Deserialize, Serialize, Clone)]
     pub struct GrepQuery {
         pub pattern: String,
@@ -1871,3 +4056,287 @@ mod models {
         pub content: String,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conflict(saved: &str, base: &str, current: &str) -> MergeHunk {
+        MergeHunk::Conflict { saved: saved.to_string(), base: base.to_string(), current: current.to_string() }
+    }
+
+    fn resolved(text: &str) -> MergeHunk {
+        MergeHunk::Resolved { text: text.to_string() }
+    }
+
+    #[test]
+    fn merge_note_merges_separate_changes() {
+        assert_eq!(merge_note("a\nb\nc\n", "A\nb\nc\n", "a\nb\nC\n"), Ok("A\nb\nC\n".to_string()));
+        assert_eq!(merge_note("a\nb\nc", "A\nb\nc", "a\nb\nC"), Ok("A\nb\nC".to_string()));
+    }
+
+    #[test]
+    fn merge_note_keeps_changes_to_the_last_newline() {
+        assert_eq!(merge_note("a\nb", "A\nb", "a\nb\n"), Ok("A\nb\n".to_string()));
+        assert_eq!(merge_note("a\nb\n", "a\nb", "A\nb\n"), Ok("A\nb".to_string()));
+        assert_eq!(merge_note("", "a", ""), Ok("a".to_string()));
+    }
+
+    #[test]
+    fn merge_note_splits_conflicts_into_hunks() {
+        assert_eq!(
+            merge_note("a\nb\nc\n", "a\nB\nc\n", "a\nX\nc\n"),
+            Err(vec![resolved("a\n"), conflict("B\n", "b\n", "X\n"), resolved("c\n")]),
+        );
+    }
+
+    #[test]
+    fn merge_note_takes_identical_changes_once() {
+        assert_eq!(merge_note("a\nb\nc\n", "a\nX\nc\n", "a\nX\nc\n"), Ok("a\nX\nc\n".to_string()));
+        assert_eq!(merge_note("a\nb\n", "a\n", "a\n"), Ok("a\n".to_string()));
+    }
+
+    #[test]
+    fn merge_note_finds_conflicting_insertions_and_deletions() {
+        assert_eq!(
+            merge_note("a\nc\n", "a\nb1\nc\n", "a\nb2\nc\n"),
+            Err(vec![resolved("a\n"), conflict("b1\n", "", "b2\n"), resolved("c\n")]),
+        );
+        assert_eq!(
+            merge_note("a\nb\nc\n", "a\nc\n", "a\nB\nc\n"),
+            Err(vec![resolved("a\n"), conflict("", "b\n", "B\n"), resolved("c\n")]),
+        );
+    }
+
+    #[test]
+    fn merge_note_finds_conflicts_on_last_lines_without_newline() {
+        assert_eq!(merge_note("a\nb", "a\nc", "a\nd"), Err(vec![resolved("a\n"), conflict("c", "b", "d")]));
+        assert_eq!(merge_note("a\nb", "a\nc\n", "a\nd"), Err(vec![resolved("a\n"), conflict("c\n", "b", "d")]));
+        assert_eq!(merge_note("b", "c", "d"), Err(vec![conflict("c", "b", "d")]));
+    }
+
+    #[test]
+    fn note_bases_are_versions_of_the_note() {
+        let dir = tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        let signature = git2::Signature::now("test", "test@example.com").unwrap();
+        let mut parents = Vec::new();
+        let mut blobs = Vec::new();
+        for (path, content) in [("a.md", "first"), ("a.md", "second"), ("secret.md", "secret")] {
+            let mut index = index_of(parents.first()).unwrap();
+            let blob = repo.blob(content.as_bytes()).unwrap();
+            index.add(&blob_index_entry(path, blob)).unwrap();
+            let tree = repo.find_tree(index.write_tree_to(&repo).unwrap()).unwrap();
+            let parent_refs: Vec<_> = parents.iter().collect();
+            let oid = repo.commit(Some("HEAD"), &signature, &signature, "m", &tree, &parent_refs).unwrap();
+            parents = vec![repo.find_commit(oid).unwrap()];
+            blobs.push(blob);
+        }
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+
+        assert_eq!(find_note_base(&repo, Some(&head), "a.md", blobs[0]).unwrap().content(), b"first");
+        assert_eq!(find_note_base(&repo, Some(&head), "a.md", blobs[1]).unwrap().content(), b"second");
+        // A blob of another note or a dangling blob must not leak through a conflict
+        let dangling = repo.blob(b"dangling").unwrap();
+        for base in [blobs[2], dangling] {
+            assert!(matches!(find_note_base(&repo, Some(&head), "a.md", base), Err(AppError::BadRequest(_))));
+        }
+        assert!(matches!(find_note_base(&repo, None, "a.md", blobs[0]), Err(AppError::BadRequest(_))));
+    }
+
+    #[test]
+    fn repo_path_accepts_relative_paths() {
+        assert_eq!(RepoPath::new("a.md").unwrap().as_str(), "a.md");
+        assert_eq!(RepoPath::new("notes/sub/a.md").unwrap().as_str(), "notes/sub/a.md");
+        assert_eq!(RepoPath::new("notes/sub/").unwrap().as_str(), "notes/sub");
+        assert_eq!(RepoPath::new(".hidden/a..b.md").unwrap().as_str(), ".hidden/a..b.md");
+        assert_eq!(RepoPath::new("a.git/.gitignore").unwrap().as_str(), "a.git/.gitignore");
+    }
+
+    #[test]
+    fn repo_path_rejects_unsafe_paths() {
+        assert!(matches!(RepoPath::new(""), Err(InvalidRepoPath::Empty)));
+        assert!(matches!(RepoPath::new("/etc/passwd"), Err(InvalidRepoPath::Absolute(_))));
+        assert!(matches!(RepoPath::new("/"), Err(InvalidRepoPath::Absolute(_))));
+        assert!(matches!(RepoPath::new("a\0b.md"), Err(InvalidRepoPath::NulByte(_))));
+        assert!(matches!(RepoPath::new("a//b.md"), Err(InvalidRepoPath::EmptyComponent(_))));
+        assert!(matches!(RepoPath::new(".."), Err(InvalidRepoPath::Traversal(_))));
+        assert!(matches!(RepoPath::new("a/../../b.md"), Err(InvalidRepoPath::Traversal(_))));
+        assert!(matches!(RepoPath::new("./a.md"), Err(InvalidRepoPath::Traversal(_))));
+        assert!(matches!(RepoPath::new(".git/config"), Err(InvalidRepoPath::Reserved(_))));
+        assert!(matches!(RepoPath::new("a/.GIT/hooks/pre-commit"), Err(InvalidRepoPath::Reserved(_))));
+    }
+
+    #[test]
+    fn repo_path_is_validated_when_deserialized() {
+        let path: RepoPath = serde_json::from_str("\"notes/a.md\"").unwrap();
+        assert_eq!(path.as_str(), "notes/a.md");
+        assert!(serde_json::from_str::<RepoPath>("\"../a.md\"").is_err());
+        assert_eq!(serde_json::to_string(&path).unwrap(), "\"notes/a.md\"");
+    }
+
+    #[test]
+    fn revision_dates_are_rfc3339_or_dates_in_utc() {
+        assert_eq!(parse_revision_date("2024-03-01T12:00:00Z").unwrap(), 1709294400);
+        assert_eq!(parse_revision_date("2024-03-01T21:00:00+09:00").unwrap(), 1709294400);
+        assert_eq!(parse_revision_date("2024-03-01").unwrap(), 1709251200);
+        for date in ["", "2024-03", "2024-02-30", "2024-03-01 12:00:00", "yesterday"] {
+            assert!(matches!(parse_revision_date(date), Err(AppError::BadRequest(_))), "{}", date);
+        }
+    }
+
+    #[test]
+    fn revisions_resolve_to_commits() {
+        let dir = tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        let tree = repo.find_tree(Index::new().unwrap().write_tree_to(&repo).unwrap()).unwrap();
+        let mut commits = Vec::new();
+        for time in [1709251200, 1709294400] {
+            let signature = git2::Signature::new("test", "test@example.com", &git2::Time::new(time, 0)).unwrap();
+            let parents: Vec<_> = commits.iter().map(|oid| repo.find_commit(*oid).unwrap()).collect();
+            let parents: Vec<_> = parents.iter().collect();
+            commits.push(repo.commit(Some("HEAD"), &signature, &signature, "m", &tree, &parents).unwrap());
+        }
+        repo.tag_lightweight("v1", &repo.find_object(commits[0], None).unwrap(), false).unwrap();
+
+        assert_eq!(resolve_revision(&repo, &commits[0].to_string()).unwrap(), commits[0]);
+        assert_eq!(resolve_revision(&repo, "v1").unwrap(), commits[0]);
+        assert_eq!(resolve_revision(&repo, "HEAD~1").unwrap(), commits[0]);
+        assert!(matches!(resolve_revision(&repo, "no-such-branch"), Err(AppError::NotFound)));
+
+        assert_eq!(resolve_revision(&repo, "@{2024-03-01T11:59:59Z}").unwrap(), commits[0]);
+        assert_eq!(resolve_revision(&repo, "@{2024-03-01T12:00:00Z}").unwrap(), commits[1]);
+        assert_eq!(resolve_revision(&repo, "@{2024-03-02}").unwrap(), commits[1]);
+        // A date alone means the start of the day, at which the first commit was made
+        assert_eq!(resolve_revision(&repo, "@{2024-03-01}").unwrap(), commits[0]);
+        assert!(matches!(resolve_revision(&repo, "@{2024-02-29}"), Err(AppError::NotFound)));
+        assert!(matches!(resolve_revision(&repo, "@{tomorrow}"), Err(AppError::BadRequest(_))));
+    }
+
+    #[tokio::test]
+    async fn coalesced_saves_can_be_reverted() {
+        env::set_var("MORIED_AUTOSAVE_COALESCE_MINUTES", "5");
+        let (state, _dir) = testing::app_state().await;
+        let principal = testing::principal("alice");
+        let save = |path: &str, content: &str| {
+            let state = state.clone();
+            let principal = principal.clone();
+            let path = RepoPath::new(path).unwrap();
+            let note_save = NoteSave::Save { content: content.to_string(), message: "save".to_string(), base: None };
+            async move {
+                put_notes_path(
+                    AppPath(path),
+                    extract::State(state),
+                    extract::Extension(principal),
+                    HeaderMap::new(),
+                    AppJson(note_save),
+                ).await.unwrap();
+            }
+        };
+        let revert = |commit_id: Oid| v2::post_revert(
+            extract::State(state.clone()),
+            extract::Extension(principal.clone()),
+            AppJson(CommitRevert { commit_id: commit_id.to_string() }),
+        );
+        let head = || state.repo.lock().unwrap().head().unwrap().target().unwrap();
+
+        save("a.md", "a").await;
+        save("b.md", "first").await;
+        let first = head();
+        save("b.md", "second").await;
+        let amended = head();
+        assert_ne!(amended, first);
+        assert_eq!(state.repo.lock().unwrap().find_commit(amended).unwrap().parent_count(), 1);
+
+        assert!(revert(amended).await.is_ok());
+        let pushed = {
+            let repo = state.repo.lock().unwrap();
+            let head_commit = repo.head().unwrap().peel_to_commit().unwrap();
+            let tree = head_commit.tree().unwrap();
+            assert!(tree.get_path(Path::new("b.md")).is_err());
+
+            // A commit pushed by someone else cannot be reverted
+            let signature = git2::Signature::now("bob", "bob@example.com").unwrap();
+            repo.commit(Some("HEAD"), &signature, &signature, "pushed", &tree, &[&head_commit]).unwrap()
+        };
+        assert!(matches!(revert(pushed).await, Err(AppError::BadRequest(_))));
+    }
+
+    #[test]
+    fn set_head_moves_only_from_the_expected_commit() {
+        let dir = tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        let signature = git2::Signature::now("test", "test@example.com").unwrap();
+        let tree = repo.find_tree(Index::new().unwrap().write_tree_to(&repo).unwrap()).unwrap();
+        let first = repo.commit(None, &signature, &signature, "first", &tree, &[]).unwrap();
+
+        set_head(&repo, first, None, "first").unwrap();
+        assert!(matches!(set_head(&repo, first, None, "first"), Err(AppError::Conflict { .. })));
+
+        let first_commit = repo.find_commit(first).unwrap();
+        let second = repo.commit(None, &signature, &signature, "second", &tree, &[&first_commit]).unwrap();
+        let third = repo.commit(None, &signature, &signature, "third", &tree, &[&first_commit]).unwrap();
+        set_head(&repo, second, Some(first), "second").unwrap();
+        // A commit on the old HEAD must not replace the one made meanwhile
+        assert!(matches!(set_head(&repo, third, Some(first), "third"), Err(AppError::Conflict { .. })));
+        assert_eq!(repo.head().unwrap().target(), Some(second));
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    /// State over an empty repository and a fresh in-memory cache database.
+    pub async fn app_state() -> (AppState, tempfile::TempDir) {
+        env::set_var("MORIED_SECRET", "test secret");
+        let dir = tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        // The identity the server commits as
+        let mut config = repo.config().unwrap();
+        config.set_str("user.name", "moried").unwrap();
+        config.set_str("user.email", "moried@example.com").unwrap();
+        // One connection, so that readers and the writer see the same in-memory database
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect_with(SqliteConnectOptions::from_str("sqlite::memory:").unwrap())
+            .await
+            .unwrap();
+        init_cache_database(&mut pool.acquire().await.unwrap()).await.unwrap();
+        let (tx, _) = watch::channel(CacheState::Fresh(Oid::zero()));
+        let state = AppState {
+            repo: Arc::new(Mutex::new(repo)),
+            cache_db: pool.clone(),
+            cache_db_writer: pool,
+            tx,
+            login_throttle: Arc::new(Mutex::new(LoginThrottle::from_env())),
+            autosave: Arc::new(Mutex::new(AutosaveCoalescing::from_env())),
+            signer: None,
+            http_client: reqwest::Client::new(),
+        };
+        (state, dir)
+    }
+
+    /// A user authenticated with a session, without ACL restrictions.
+    pub fn principal(user: &str) -> Principal {
+        Principal {
+            user: user.to_string(),
+            email: format!("{}@example.com", user),
+            display_name: None,
+            session_id: Some("session".to_string()),
+            token: None,
+            acl: PathAccess::default(),
+        }
+    }
+
+    /// Add an account with a password.
+    pub async fn add_user(state: &AppState, name: &str, email: &str, password: &str) {
+        sqlx::query("INSERT INTO user (name, email, hash, created_at) VALUES (?, ?, ?, 0);")
+            .bind(name)
+            .bind(email)
+            .bind(hash_password(password).unwrap())
+            .execute(&state.cache_db_writer)
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,198 @@
+//! Deleted paths, kept restorable for a retention period.
+
+use super::*;
+use chrono::offset::TimeZone;
+use sqlx::sqlite::SqliteRow;
+
+fn trash_retention() -> Duration {
+    env::var("MORIED_TRASH_RETENTION_DAYS").map_or(Duration::days(30), |v| {
+        Duration::days(v.parse::<i64>().expect("Trash retention in days represented as integer value is expected"))
+    })
+}
+
+/// A path removed by a commit.
+#[derive(Debug, Clone)]
+pub struct DeletedPath {
+    pub path: String,
+    pub blob_id: Oid,
+    /// The file mode of the index entry, such as executable or symbolic link.
+    pub mode: u32,
+}
+
+/// A deleted path that can be restored.
+#[derive(Debug, Serialize, Clone)]
+pub struct TrashEntry {
+    pub id: i64,
+    pub path: String,
+    /// The content of the path when it was deleted.
+    pub blob_id: String,
+    pub mode: u32,
+    /// The commit that deleted the path.
+    pub commit_id: String,
+    pub deleted_by: String,
+    pub deleted_at: DateTime<Utc>,
+}
+
+impl TrashEntry {
+    fn from_row(row: SqliteRow) -> Self {
+        TrashEntry {
+            id: row.get("id"),
+            path: row.get("path"),
+            blob_id: row.get("blob_id"),
+            mode: row.get("mode"),
+            commit_id: row.get("commit_id"),
+            deleted_by: row.get("deleted_by"),
+            deleted_at: Utc.timestamp_opt(row.get("deleted_at"), 0).unwrap(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct TrashQuery {
+    /// Matches the path and everything under it.
+    pub path: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct TrashRestore {
+    /// A deleted file, or a directory to restore what was deleted under it.
+    pub path: RepoPath,
+    /// The commit whose deletions to restore, by default the last one under `path`.
+    pub commit_id: Option<String>,
+}
+
+impl AppState {
+    /// Record the paths deleted by a commit, and purge entries older than the retention period.
+    pub async fn record_trash(&self, commit_id: Oid, user: &str, deleted: &[DeletedPath]) -> Result<()> {
+        let now = Utc::now();
+        let mut tx = self.cache_db_writer.begin().await?;
+        for deleted in deleted {
+            sqlx::query("INSERT INTO trash (path, blob_id, mode, commit_id, deleted_by, deleted_at) VALUES (?, ?, ?, ?, ?, ?);")
+                .bind(&deleted.path)
+                .bind(deleted.blob_id.to_string())
+                .bind(deleted.mode)
+                .bind(commit_id.to_string())
+                .bind(user)
+                .bind(now.timestamp())
+                .execute(&mut *tx)
+                .await
+                .context("Failed to record a deleted path")?;
+        }
+        let purged = sqlx::query("DELETE FROM trash WHERE deleted_at < ?;")
+            .bind((now - trash_retention()).timestamp())
+            .execute(&mut *tx)
+            .await
+            .context("Failed to purge the trash")?;
+        tx.commit().await?;
+        if purged.rows_affected() > 0 {
+            tracing::info!("Purged {} paths from the trash", purged.rows_affected());
+        }
+        Ok(())
+    }
+
+    /// Newest deletions first, limited to `path` and everything under it if given.
+    pub async fn list_trash(&self, path: Option<&str>) -> Result<Vec<TrashEntry>> {
+        let entries = sqlx::query("
+                SELECT * FROM trash
+                WHERE deleted_at >= ?1
+                    AND (?2 IS NULL OR path = ?2 OR substr(path, 1, length(?2) + 1) = ?2 || '/')
+                ORDER BY id DESC;
+            ")
+            .bind((Utc::now() - trash_retention()).timestamp())
+            .bind(path)
+            .map(TrashEntry::from_row)
+            .fetch_all(&self.cache_db)
+            .await?;
+        Ok(entries)
+    }
+
+    /// Remove restored entries, along with earlier deletions of the same paths.
+    pub async fn remove_from_trash(&self, entries: &[&TrashEntry]) -> Result<()> {
+        let mut tx = self.cache_db_writer.begin().await?;
+        for entry in entries {
+            sqlx::query("DELETE FROM trash WHERE path = ? AND id <= ?;")
+                .bind(&entry.path)
+                .bind(entry.id)
+                .execute(&mut *tx)
+                .await
+                .context("Failed to remove a path from the trash")?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+}
+
+pub async fn get_trash(
+    extract::State(state): extract::State<AppState>,
+    extract::Extension(principal): extract::Extension<Principal>,
+    AppQuery(query): AppQuery<TrashQuery>,
+) -> Result<Json<Vec<TrashEntry>>, AppError> {
+    tracing::debug!("trash::get_trash");
+    let entries = state.list_trash(query.path.as_deref()).await?
+        .into_iter()
+        .filter(|entry| principal.permits(&Method::GET, "/v2/trash", &entry.path) && principal.acl.can_read(&entry.path))
+        .collect();
+    Ok(Json(entries))
+}
+
+/// Restore the paths deleted by one commit under a path, by default the last one, in a new commit.
+pub async fn post_trash_restore(
+    extract::State(state): extract::State<AppState>,
+    extract::Extension(principal): extract::Extension<Principal>,
+    AppJson(restore): AppJson<TrashRestore>,
+) -> Result<Response, AppError> {
+    tracing::debug!("trash::post_trash_restore");
+
+    // Entries are listed newest first, so the first one is of the last deletion
+    let entries = state.list_trash(Some(&restore.path)).await?;
+    let Some(commit_id) = restore.commit_id.as_ref().or(entries.first().map(|entry| &entry.commit_id)).cloned() else {
+        return Err(AppError::NotFound);
+    };
+    let mut latest: BTreeMap<String, TrashEntry> = BTreeMap::new();
+    for entry in entries.into_iter().filter(|entry| entry.commit_id == commit_id) {
+        latest.entry(entry.path.clone()).or_insert(entry);
+    }
+    if latest.is_empty() {
+        return Err(AppError::NotFound);
+    }
+    if !latest.keys().all(|path| principal.permits(&Method::POST, "/v2/trash/restore", path) && principal.acl.can_write(path)) {
+        return Err(AppError::Forbidden);
+    }
+
+    let commit_oid = {
+        let repo = state.repo.lock().unwrap();
+
+        let head_commit = head_commit(&repo)?;
+        let mut index = index_of(head_commit.as_ref())?;
+
+        // Paths created again since their deletion are not overwritten
+        let existing: Vec<String> = latest.keys()
+            .filter(|path| !index_paths_under(&index, path).is_empty())
+            .cloned()
+            .collect();
+        if !existing.is_empty() {
+            return Err(AppError::conflict("some of the paths exist", ConflictingPaths { paths: existing }));
+        }
+
+        for entry in latest.values() {
+            let blob_oid = Oid::from_str(&entry.blob_id)?;
+            index.add(&IndexEntry { mode: entry.mode, ..blob_index_entry(&entry.path, blob_oid) })?;
+        }
+
+        let tree_oid = index.write_tree_to(&repo)?;
+        let tree = repo.find_tree(tree_oid)?;
+
+        let author = principal.signature()?;
+        let parents: Vec<_> = head_commit.iter().collect();
+        commit_to_head(&repo, state.signer.as_deref(), &author, &format!("Restore {}", restore.path), &tree, &parents)?
+    };
+    tracing::info!("User '{}' restored {} paths under {} as {}", principal.user, latest.len(), restore.path, commit_oid);
+
+    state.remove_from_trash(&latest.values().collect::<Vec<_>>()).await?;
+
+    let created = CreatedCommit {
+        commit_id: commit_oid.to_string(),
+        paths: latest.into_keys().collect(),
+    };
+    Ok((Extension(CommitCreated(commit_oid)), Json(created)).into_response())
+}
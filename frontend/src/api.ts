@@ -128,13 +128,24 @@ export function compareTags(a: string, b: string): number {
 }
 
 // APIs
-export function login(user: string, password: string) {
+export function login(user: string, password: string, otp: string | null) {
   return getAxios().post(`/login`, {
     user: user,
     password: password,
+    otp: otp,
   });
 }
 
+export function refresh(refreshToken: string) {
+  return getAxios().post(`/refresh`, {
+    refresh_token: refreshToken,
+  });
+}
+
+export function logout() {
+  return getAxios().post(`/logout`);
+}
+
 export function listNotes() {
   return getAxios().get('/notes');
 }
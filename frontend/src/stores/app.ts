@@ -10,9 +10,11 @@ import * as api from '@/api';
 export const useAppStore = defineStore('app', () => {
   // States
   const token = useLocalStorage<string | null>('token', null);
+  const refreshToken = useLocalStorage<string | null>('refreshToken', null);
   const loginCallbacks: Ref<(() => void)[]> = ref([]);
   const isLoggingIn = ref(false);
   const loginError: Ref<null | string> = ref(null);
+  const otpRequired = ref(false);
   const serviceWorker: Ref<null | ServiceWorker> = ref(null);
   const serviceWorkerConfigured = ref(false);
   const serviceWorkerHasToken = ref(false);
@@ -21,41 +23,92 @@ export const useAppStore = defineStore('app', () => {
   // Getters
   const hasToken = computed(() => !!token.value);
 
+  // A refresh in progress, which requests failing meanwhile wait for
+  let refreshing: Promise<void> | null = null;
+
   // Actions
   function invalidateToken(callback: () => void) {
     loginCallbacks.value.push(callback);
 
-    // Delete the token and let a user to login again
-    logout();
+    // A refresh token can be used only once, so concurrent callers share one refresh
+    if (refreshing !== null) {
+      return;
+    }
+
+    const failedRefreshToken = refreshToken.value;
+    if (failedRefreshToken === null) {
+      // Delete the token and let a user to login again
+      clearTokens();
+      return;
+    }
+
+    // Try to continue the session with the refresh token first
+    refreshing = api.refresh(failedRefreshToken).then(res => {
+      setTokens(res.data.access_token, res.data.refresh_token);
+    }).catch(_error => {
+      // Delete the token and let a user to login again, unless new tokens have been obtained meanwhile
+      if (refreshToken.value === failedRefreshToken) {
+        clearTokens();
+      }
+    }).finally(() => {
+      refreshing = null;
+    });
   }
 
-  function login(username: string, password: string) {
+  function login(username: string, password: string, otp: string) {
     isLoggingIn.value = true;
 
     api.login(
       username,
       password,
+      otpRequired.value ? otp : null,
     ).then(res => {
-      token.value = res.data;
+      setTokens(res.data.access_token, res.data.refresh_token);
 
       isLoggingIn.value = false;
       loginError.value = null;
-
-      if (serviceWorker.value) {  // FIXME This should be executed after service worker get ready
-        serviceWorker.value.postMessage({
-          type: 'update-api-token',
-          value: token.value,
-        });
-      }
-    }).catch(_error => {
+      otpRequired.value = false;
+    }).catch(error => {
       isLoggingIn.value = false;
-      loginError.value = "Incorrect username or password";
+      if (error.response && error.response.data && error.response.data.otp_required) {
+        // The password was correct, so ask for the second factor
+        otpRequired.value = true;
+        loginError.value = null;
+      }
+      else if (otpRequired.value) {
+        loginError.value = "Incorrect one-time password";
+      }
+      else {
+        loginError.value = "Incorrect username or password";
+      }
     });
   }
 
   function logout() {
+    // Revoke the session on the server as well
+    if (token.value !== null) {
+      api.logout().catch(_error => {});
+    }
+    clearTokens();
+  }
+
+  function setTokens(newToken: string, newRefreshToken: string) {
+    token.value = newToken;
+    refreshToken.value = newRefreshToken;
+
+    // Let service worker know it
+    if (serviceWorker.value) {  // FIXME This should be executed after service worker get ready
+      serviceWorker.value.postMessage({
+        type: 'update-api-token',
+        value: token.value,
+      });
+    }
+  }
+
+  function clearTokens() {
     // Delete the current token
     token.value = null;
+    refreshToken.value = null;
 
     // Let service worker know it
     if (serviceWorker.value) {  // FIXME This should be executed after service worker get ready
@@ -114,6 +167,7 @@ export const useAppStore = defineStore('app', () => {
     loginCallbacks,
     isLoggingIn,
     loginError,
+    otpRequired,
     serviceWorker,
     serviceWorkerConfigured,
     serviceWorkerHasToken,
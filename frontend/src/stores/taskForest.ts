@@ -439,32 +439,14 @@ export const useTaskForestStore = defineStore('taskForest', () => {
         // Move the task itself first
         await renameNote(oldPath, newPath);
         
-        // Recursively move all descendants using manual path calculation
-        await moveDescendantFiles(taskUuid, oldPath, newPath);
-    }
-
-    async function moveDescendantFiles(parentUuid: UUID, oldParentPath: string, newParentPath: string): Promise<void> {
-        const { renameNote } = await import('@/api');
-        const children = childrenOf(parentUuid);
-        
-        for (const child of children) {
+        // Move the directory of its descendants at once, which the server renames recursively
+        if (childrenOf(taskUuid).length > 0) {
+            const oldDir = oldPath.replace('.md', '');
+            const newDir = newPath.replace('.md', '');
             try {
-                // Calculate the old and new parent directory paths for children
-                const oldParentDir = oldParentPath.replace('.md', '');
-                const newParentDir = newParentPath.replace('.md', '');
-                
-                // Build child paths
-                const childOldPath = `${oldParentDir}/${child.uuid}.md`;
-                const childNewPath = `${newParentDir}/${child.uuid}.md`;
-                
-                // Move the child file
-                await renameNote(childOldPath, childNewPath);
-                
-                // Recursively move the child's descendants
-                await moveDescendantFiles(child.uuid, childOldPath, childNewPath);
+                await renameNote(oldDir, newDir);
             } catch (error) {
-                console.warn(`Failed to move child ${child.uuid}:`, error);
-                // Continue with other children even if one fails
+                console.warn(`Failed to move descendants of ${taskUuid}:`, error);
             }
         }
     }